@@ -0,0 +1,183 @@
+//! Abstraction over the audio backend the engine runs on.
+//!
+//! JACK (`JackBackend`) is the default, but anything implementing `Backend` can be used - see
+//! `offline::OfflineBackend` for one that renders audio without a server.
+
+use sqa_jack::*;
+use std::mem;
+use std::sync::Arc;
+use errors::{EngineResult, EngineError};
+use clock::{Clock, MonotonicClock, JackClock};
+pub use thread::{CallbackContext, DeviceContext};
+
+/// An audio backend, capable of registering ports and running the audio thread.
+pub trait Backend {
+    /// The type of port this backend registers.
+    type Port: Clone + Send + 'static;
+    /// Register a new output port with a given name.
+    fn register_port(&mut self, name: &str) -> EngineResult<Self::Port>;
+    /// Register a new input port with a given name.
+    fn register_input_port(&mut self, name: &str) -> EngineResult<Self::Port>;
+    /// Unregister a port, disconnecting any connections it may have.
+    fn unregister_port(&mut self, port: Self::Port) -> EngineResult<()>;
+    /// Connect a port to another port (which may not belong to this backend), given the other
+    /// port's name.
+    ///
+    /// Output ports are connected so that audio flows to the other port, and input ports so
+    /// that audio flows from it.
+    fn connect_port(&mut self, port: &Self::Port, to: &str) -> EngineResult<()>;
+    /// Disconnect a port from another port, given the other port's name.
+    fn disconnect_port(&mut self, port: &Self::Port, from: &str) -> EngineResult<()>;
+    /// Get the sample rate the backend runs at, in Hz.
+    fn sample_rate(&self) -> u64;
+    /// Get the number of frames processed in each run of the audio callback.
+    fn buffer_size(&self) -> u32;
+    /// Get the clock the engine should use if it isn't given one explicitly.
+    fn default_clock(&self) -> Arc<dyn Clock> {
+        Arc::new(MonotonicClock)
+    }
+    /// Start running the audio callback, calling `process_at()` on the given handler once per
+    /// period.
+    ///
+    /// This will only be called once.
+    fn start(&mut self, handler: DeviceContext<Self::Port>) -> EngineResult<()>;
+}
+
+enum JackState {
+    Deactivated(JackConnection<Deactivated>),
+    Activated(JackConnection<Activated>),
+    Poisoned
+}
+/// The JACK audio backend.
+pub struct JackBackend {
+    conn: JackState
+}
+impl JackBackend {
+    /// Open a connection to JACK, under a given name.
+    ///
+    /// The JACK server will not be started if it isn't running already.
+    pub fn new(name: &str) -> EngineResult<Self> {
+        let conn = JackConnection::connect(name, Some(OPEN_NO_START_SERVER))?;
+        Ok(JackBackend {
+            conn: JackState::Deactivated(conn)
+        })
+    }
+    /// Get the underlying JACK connection, for JACK-specific functionality.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend hasn't been started (which never happens to a `JackBackend` owned by
+    /// an `EngineContext`).
+    pub fn conn(&mut self) -> &mut JackConnection<Activated> {
+        match self.conn {
+            JackState::Activated(ref mut c) => c,
+            _ => panic!("JackBackend::conn() called before the backend was started")
+        }
+    }
+    /// Get a `JackClock` for this backend's connection, for use with
+    /// `EngineContext::with_backend_and_clock()`.
+    ///
+    /// Returns `None` if the backend is in an invalid state.
+    pub fn jack_clock(&self) -> Option<JackClock> {
+        let ptr = match self.conn {
+            JackState::Deactivated(ref c) => c.as_ptr(),
+            JackState::Activated(ref c) => c.as_ptr(),
+            JackState::Poisoned => return None
+        };
+        // The audio thread only runs while the connection (owned by this backend) is alive.
+        Some(unsafe { JackClock::from_ptr(ptr) })
+    }
+    fn register_port_with_flags(&mut self, name: &str, flags: JackPortFlags) -> EngineResult<JackPort> {
+        Ok(match self.conn {
+            JackState::Deactivated(ref mut c) => c.register_port(name, flags)?,
+            JackState::Activated(ref mut c) => c.register_port(name, flags)?,
+            JackState::Poisoned => Err(EngineError::InvalidBackendState)?
+        })
+    }
+}
+impl Backend for JackBackend {
+    type Port = JackPort;
+    fn register_port(&mut self, name: &str) -> EngineResult<JackPort> {
+        self.register_port_with_flags(name, PORT_IS_OUTPUT | PORT_IS_TERMINAL)
+    }
+    fn register_input_port(&mut self, name: &str) -> EngineResult<JackPort> {
+        self.register_port_with_flags(name, PORT_IS_INPUT | PORT_IS_TERMINAL)
+    }
+    fn unregister_port(&mut self, port: JackPort) -> EngineResult<()> {
+        match self.conn {
+            JackState::Deactivated(ref mut c) => c.unregister_port(port)?,
+            JackState::Activated(ref mut c) => c.unregister_port(port)?,
+            JackState::Poisoned => Err(EngineError::InvalidBackendState)?
+        }
+        Ok(())
+    }
+    fn connect_port(&mut self, port: &JackPort, to: &str) -> EngineResult<()> {
+        match self.conn {
+            JackState::Deactivated(_) => Err(EngineError::InvalidBackendState)?,
+            JackState::Activated(ref mut c) => {
+                let to = c.get_port_by_name(to)?;
+                if port.get_flags().contains(PORT_IS_INPUT) {
+                    c.connect_ports(&to, port)?;
+                }
+                else {
+                    c.connect_ports(port, &to)?;
+                }
+            },
+            JackState::Poisoned => Err(EngineError::InvalidBackendState)?
+        }
+        Ok(())
+    }
+    fn disconnect_port(&mut self, port: &JackPort, from: &str) -> EngineResult<()> {
+        match self.conn {
+            JackState::Deactivated(_) => Err(EngineError::InvalidBackendState)?,
+            JackState::Activated(ref mut c) => {
+                let from = c.get_port_by_name(from)?;
+                if port.get_flags().contains(PORT_IS_INPUT) {
+                    c.disconnect_ports(&from, port)?;
+                }
+                else {
+                    c.disconnect_ports(port, &from)?;
+                }
+            },
+            JackState::Poisoned => Err(EngineError::InvalidBackendState)?
+        }
+        Ok(())
+    }
+    fn sample_rate(&self) -> u64 {
+        match self.conn {
+            JackState::Deactivated(ref c) => c.sample_rate() as u64,
+            JackState::Activated(ref c) => c.sample_rate() as u64,
+            JackState::Poisoned => 0
+        }
+    }
+    fn buffer_size(&self) -> u32 {
+        match self.conn {
+            JackState::Deactivated(ref c) => c.buffer_size(),
+            JackState::Activated(ref c) => c.buffer_size(),
+            JackState::Poisoned => 0
+        }
+    }
+    fn start(&mut self, handler: DeviceContext<JackPort>) -> EngineResult<()> {
+        let mut conn = match mem::replace(&mut self.conn, JackState::Poisoned) {
+            JackState::Deactivated(c) => c,
+            x => {
+                self.conn = x;
+                Err(EngineError::InvalidBackendState)?
+            }
+        };
+        if let Err(e) = conn.set_handler(handler) {
+            self.conn = JackState::Deactivated(conn);
+            return Err(e.into());
+        }
+        match conn.activate() {
+            Ok(c) => {
+                self.conn = JackState::Activated(c);
+                Ok(())
+            },
+            Err((c, err)) => {
+                self.conn = JackState::Deactivated(c);
+                Err(err.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Bouncing the output of the engine's channels to WAV files.
+//!
+//! This can happen either alongside live playback (see `EngineContext::start_bounce()`), where a
+//! tap in the audio thread feeds a writer thread, or offline (see
+//! `EngineContext::<OfflineBackend>::render_to()`), where you write each period as you render it.
+//!
+//! Input channels can be recorded to WAV files in the same way, with
+//! `EngineContext::start_recording()`.
+
+use hound::{self, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::*;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use bounded_spsc_queue::Consumer;
+use errors::{EngineResult, EngineError};
+use input::CaptureGuard;
+
+/// The format samples are written to disk in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BounceFormat {
+    /// 16-bit signed integer.
+    Int16,
+    /// 24-bit signed integer.
+    Int24,
+    /// 32-bit signed integer.
+    Int32,
+    /// 32-bit IEEE floating point.
+    Float32
+}
+impl BounceFormat {
+    fn bits(&self) -> u16 {
+        use self::BounceFormat::*;
+        match *self {
+            Int16 => 16,
+            Int24 => 24,
+            Int32 | Float32 => 32
+        }
+    }
+}
+/// How the channels of a bounce are laid out on disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BounceLayout {
+    /// One WAV file, with one WAV channel per bounced channel.
+    Multichannel,
+    /// One mono WAV file per bounced channel.
+    ///
+    /// The files are named after the path given, with the channel number appended to the file
+    /// stem (so `show.wav` becomes `show-0.wav`, `show-1.wav`, and so on).
+    PerChannel
+}
+/// Details of how to write a bounce to disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BounceSpec {
+    pub format: BounceFormat,
+    pub layout: BounceLayout
+}
+
+/// Writes audio from a set of channels to WAV file(s).
+pub struct BounceWriter {
+    chans: Vec<usize>,
+    writers: Vec<WavWriter<BufWriter<File>>>,
+    spec: BounceSpec
+}
+impl BounceWriter {
+    /// Make a new writer for a set of channel numbers, creating the WAV file(s) at `path`.
+    ///
+    /// Duplicate channel numbers will be removed.
+    pub fn new<P: AsRef<Path>>(path: P, chans: &[usize], sample_rate: u64, spec: BounceSpec) -> EngineResult<Self> {
+        let mut uniq: Vec<usize> = vec![];
+        for &ch in chans {
+            if !uniq.contains(&ch) {
+                uniq.push(ch);
+            }
+        }
+        let wav_spec = |channels| WavSpec {
+            channels,
+            sample_rate: sample_rate as u32,
+            bits_per_sample: spec.format.bits(),
+            sample_format: if spec.format == BounceFormat::Float32 {
+                hound::SampleFormat::Float
+            } else {
+                hound::SampleFormat::Int
+            }
+        };
+        let path = path.as_ref();
+        let mut writers = vec![];
+        match spec.layout {
+            BounceLayout::Multichannel => {
+                writers.push(WavWriter::create(path, wav_spec(uniq.len() as u16))?);
+            },
+            BounceLayout::PerChannel => {
+                for ch in uniq.iter() {
+                    writers.push(WavWriter::create(per_channel_path(path, *ch), wav_spec(1))?);
+                }
+            }
+        }
+        Ok(BounceWriter {
+            chans: uniq,
+            writers,
+            spec
+        })
+    }
+    /// Get the channel numbers this writer writes, in order.
+    pub fn chans(&self) -> &[usize] {
+        &self.chans
+    }
+    #[inline(always)]
+    fn write_sample(format: BounceFormat, wr: &mut WavWriter<BufWriter<File>>, samp: f32) -> EngineResult<()> {
+        use self::BounceFormat::*;
+        let clamped = samp.max(-1.0).min(1.0);
+        match format {
+            Int16 => wr.write_sample((clamped * ::std::i16::MAX as f32) as i16)?,
+            Int24 => wr.write_sample((clamped * 8_388_607.0) as i32)?,
+            Int32 => wr.write_sample((clamped as f64 * ::std::i32::MAX as f64) as i32)?,
+            Float32 => wr.write_sample(samp)?
+        }
+        Ok(())
+    }
+    /// Write one frame of audio, containing one sample for each channel, in order.
+    pub fn write_frame(&mut self, frame: &[f32]) -> EngineResult<()> {
+        for (i, &samp) in frame.iter().enumerate().take(self.chans.len()) {
+            let wr = match self.spec.layout {
+                BounceLayout::Multichannel => &mut self.writers[0],
+                BounceLayout::PerChannel => &mut self.writers[i]
+            };
+            Self::write_sample(self.spec.format, wr, samp)?;
+        }
+        Ok(())
+    }
+    /// Write a period of audio, given one buffer for each channel, in order.
+    ///
+    /// All buffers MUST be the same length.
+    pub fn write_buffers(&mut self, bufs: &[&[f32]]) -> EngineResult<()> {
+        let len = bufs.iter().map(|b| b.len()).min().unwrap_or(0);
+        let mut frame = vec![0.0; bufs.len()];
+        for i in 0..len {
+            for (j, buf) in bufs.iter().enumerate() {
+                frame[j] = buf[i];
+            }
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+    /// Finish writing, updating the WAV headers.
+    pub fn finalize(self) -> EngineResult<()> {
+        for wr in self.writers {
+            wr.finalize()?;
+        }
+        Ok(())
+    }
+}
+fn per_channel_path(path: &Path, ch: usize) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, ch, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, ch)
+    };
+    path.with_file_name(name)
+}
+
+/// A bounce (or recording) happening alongside live playback, with a thread writing audio to disk.
+///
+/// Obtained from `EngineContext::start_bounce()` or `EngineContext::start_recording()`.
+pub struct Bouncer {
+    done: Arc<AtomicBool>,
+    overflowed: Arc<AtomicBool>,
+    thread: JoinHandle<EngineResult<()>>
+}
+impl Bouncer {
+    /// Spawn the writer thread. If a `CaptureGuard` is given, the capture is stopped once the
+    /// thread finishes.
+    pub(crate) fn spawn(mut writer: BounceWriter, buf: Consumer<f32>, overflowed: Arc<AtomicBool>, guard: Option<CaptureGuard>) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let done2 = done.clone();
+        let thread = thread::spawn(move || {
+            let nchans = writer.chans().len();
+            let mut frame = vec![0.0; nchans];
+            let mut idx = 0;
+            loop {
+                if let Some(samp) = buf.try_pop() {
+                    frame[idx] = samp;
+                    idx += 1;
+                    if idx == nchans {
+                        writer.write_frame(&frame)?;
+                        idx = 0;
+                    }
+                }
+                else if done2.load(Relaxed) {
+                    break;
+                }
+                else {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            drop(guard);
+            writer.finalize()
+        });
+        Bouncer {
+            done,
+            overflowed,
+            thread
+        }
+    }
+    /// Whether the writer thread has fallen behind, causing audio to be dropped from the bounce.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Relaxed)
+    }
+    /// Finish the bounce, waiting for the writer thread to write out any remaining audio.
+    ///
+    /// You SHOULD call `EngineContext::stop_bounce()` before calling this, otherwise the bounce
+    /// will end at some arbitrary point. (Recordings are stopped by this function.)
+    pub fn finish(self) -> EngineResult<()> {
+        self.done.store(true, Relaxed);
+        match self.thread.join() {
+            Ok(r) => r,
+            Err(_) => Err(EngineError::BounceThreadPanicked)
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Submix buses.
+//!
+//! Streams can be routed into a bus (with `Dest::Bus` crosspoints in their routing matrix), where
+//! they're summed together, have the bus's volume applied, and are sent on to wherever the bus
+//! is routed. Buses can be routed into other buses, as long as the other bus has a lower bus
+//! number than they do - this is always the case for buses that were created earlier, unless a
+//! removed bus's number has been reused.
+
+use std::sync::Arc;
+use errors::{EngineResult, EngineError};
+use param::Parameter;
+use routing::{Dest, Routing};
+use sync::SharedValue;
+
+/// Controls a bus on the audio thread. Obtained from `EngineContext::bus()`.
+pub struct Bus {
+    pub(crate) idx: usize,
+    pub(crate) channels: usize,
+    pub(crate) volume: Arc<SharedValue<Parameter<f32>>>,
+    /// Where each of the bus's channels is sent.
+    pub(crate) routing: Arc<SharedValue<Routing>>
+}
+impl Bus {
+    /// Get this bus's bus number.
+    pub fn index(&self) -> usize {
+        self.idx
+    }
+    /// Get the number of channels this bus has.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+    /// Set the volume of this bus.
+    ///
+    /// The audio thread picks the new volume up at the start of its next period, and sends the
+    /// old one back in a `VolumeRemoved` message to be deallocated.
+    pub fn set_volume(&mut self, vol: Box<Parameter<f32>>) {
+        self.volume.set(vol);
+    }
+    /// Get the volume of this bus.
+    ///
+    /// This is the volume most recently set, which the audio thread might not have picked up yet.
+    pub fn volume(&self) -> Parameter<f32> {
+        self.volume.get()
+    }
+    /// Get this bus's routing matrix.
+    ///
+    /// This is the matrix most recently set, which the audio thread might not have picked up yet.
+    pub fn routing(&self) -> Routing {
+        self.routing.get()
+    }
+    /// Set this bus's routing matrix, replacing the old one.
+    ///
+    /// Returns `EngineError::InvalidRouting` (leaving the old matrix in place) if it routes from
+    /// a channel the bus doesn't have, or to a bus whose bus number isn't lower than this one's.
+    /// Crosspoints to channels or buses that don't exist are ignored by the audio thread.
+    ///
+    /// The audio thread picks the new matrix up at the start of its next period, and sends the
+    /// old one back in a `RoutingRemoved` message to be deallocated.
+    pub fn set_routing(&mut self, routing: Routing) -> EngineResult<()> {
+        self.check_routing(&routing)?;
+        self.routing.set(Box::new(routing));
+        Ok(())
+    }
+    /// Check that a routing matrix is valid for this bus (see `set_routing()`).
+    pub(crate) fn check_routing(&self, routing: &Routing) -> EngineResult<()> {
+        for cp in routing.points() {
+            if cp.source >= self.channels {
+                Err(EngineError::InvalidRouting)?
+            }
+            if let Dest::Bus(b, _) = cp.dest {
+                if b >= self.idx {
+                    Err(EngineError::InvalidRouting)?
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Send one of this bus's channels to a destination at a given gain, leaving the rest of the
+    /// routing matrix untouched.
+    pub fn set_crosspoint<D: Into<Dest>>(&mut self, source: usize, dest: D, gain: f32) -> EngineResult<()> {
+        let mut routing = self.routing();
+        routing.set(source, dest, gain);
+        self.set_routing(routing)
+    }
+    /// Stop sending one of this bus's channels to a destination.
+    pub fn remove_crosspoint<D: Into<Dest>>(&mut self, source: usize, dest: D) {
+        let mut routing = self.routing();
+        if routing.remove(source, dest) {
+            let _ = self.set_routing(routing);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Sources of time for the engine.
+//!
+//! Every time the engine deals with (start times, fade timing, positions) is in nanoseconds,
+//! as read from the `Clock` owned by the `EngineContext`. All times you give the engine MUST
+//! come from that same clock - use `EngineContext::now()` or `Sender::now()` to read it.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::*;
+use sqa_jack::{jack_client_t, jack_get_time, jack_frames_to_time, jack_last_frame_time};
+use time;
+
+/// A source of time, in nanoseconds.
+pub trait Clock: Send + Sync {
+    /// Get the current time, in nanoseconds.
+    fn now(&self) -> u64;
+    /// Get the time at which the current period of audio starts, in nanoseconds.
+    ///
+    /// This is only ever called from the audio thread, at the start of each period.
+    fn period_time(&self) -> u64 {
+        self.now()
+    }
+}
+
+/// The system's monotonic clock (`time::precise_time_ns()`).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MonotonicClock;
+impl Clock for MonotonicClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        time::precise_time_ns()
+    }
+}
+
+/// JACK's clock, with periods starting at the exact time of their first frame.
+///
+/// This avoids the jitter that comes from reading the monotonic clock at whatever point the
+/// audio thread happens to get scheduled.
+pub struct JackClock {
+    client: *const jack_client_t
+}
+// The client pointer is only used from the audio thread, where the client is always alive.
+unsafe impl Send for JackClock {}
+unsafe impl Sync for JackClock {}
+impl JackClock {
+    /// Make a `JackClock` from a JACK client pointer.
+    ///
+    /// # Safety
+    ///
+    /// The client MUST outlive the engine's audio thread.
+    pub unsafe fn from_ptr(client: *const jack_client_t) -> Self {
+        JackClock { client }
+    }
+}
+impl Clock for JackClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        unsafe { jack_get_time() * 1000 }
+    }
+    #[inline(always)]
+    fn period_time(&self) -> u64 {
+        unsafe { jack_frames_to_time(self.client, jack_last_frame_time(self.client)) * 1000 }
+    }
+}
+
+/// A clock that only moves when told to, for tests and offline rendering.
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    time: AtomicU64
+}
+impl VirtualClock {
+    /// Make a new `VirtualClock`, starting at a given time.
+    pub fn new(time: u64) -> Self {
+        VirtualClock {
+            time: AtomicU64::new(time)
+        }
+    }
+    /// Set the current time.
+    pub fn set(&self, time: u64) {
+        self.time.store(time, Relaxed);
+    }
+    /// Move the clock forward by `nanos` nanoseconds.
+    pub fn advance(&self, nanos: u64) {
+        self.time.fetch_add(nanos, Relaxed);
+    }
+}
+impl Clock for VirtualClock {
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        self.time.load(Relaxed)
+    }
+}
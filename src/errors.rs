@@ -1,4 +1,8 @@
+// `derive(Fail)` puts its impls inside a constant, which newer compilers warn about.
+#![allow(unknown_lints, non_local_definitions)]
+
 use sqa_jack::errors::JackError;
+use hound;
 
 pub type EngineResult<T> = Result<T, EngineError>;
 
@@ -9,10 +13,25 @@ pub enum EngineError {
     #[fail(display = "Engine channel or sender limit exceeded")]
     LimitExceeded,
     #[fail(display = "No such channel.")]
-    NoSuchChannel
+    NoSuchChannel,
+    #[fail(display = "The audio backend is in an invalid state for this operation.")]
+    InvalidBackendState,
+    #[fail(display = "Invalid routing matrix.")]
+    InvalidRouting,
+    #[fail(display = "Invalid loop region.")]
+    InvalidLoop,
+    #[fail(display = "WAV error: {}", _0)]
+    Wav(hound::Error),
+    #[fail(display = "The bounce writer thread panicked.")]
+    BounceThreadPanicked
 }
 impl From<JackError> for EngineError {
     fn from(je: JackError) -> EngineError {
         EngineError::Jack(je)
     }
 }
+impl From<hound::Error> for EngineError {
+    fn from(he: hound::Error) -> EngineError {
+        EngineError::Wav(he)
+    }
+}
@@ -0,0 +1,41 @@
+//! Transport groups, for controlling many streams at once.
+//!
+//! Calling `set_start_time()` and `set_active()` on several senders, one after another, isn't
+//! atomic: the audio thread can run partway through, so that some streams start a period before
+//! the others. Streams enrolled in a `TransportGroup` (with `Sender::set_group()`) can instead be
+//! started, paused, resumed, stopped and seeked all together, with `EngineContext::group_start()`
+//! and friends - which take effect for every member of the group in the same period.
+
+use uuid::Uuid;
+use thread::Seek;
+
+/// A group of streams whose transport is controlled together.
+///
+/// Obtained from `EngineContext::new_group()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TransportGroup {
+    pub(crate) id: u64
+}
+impl TransportGroup {
+    /// Get this group's ID, which is unique for the lifetime of its `EngineContext`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// Something to do to every member of a transport group, on the audio thread.
+pub enum GroupAction {
+    /// Start playing at a given time, like `Sender::play_from_time()`.
+    Start(u64),
+    /// Pause, like `Sender::pause()`.
+    Pause,
+    /// Resume, like `Sender::resume()`.
+    Resume,
+    /// Stop at a given time, like `Sender::stop_at()`.
+    Stop {
+        time: u64,
+        kill: bool
+    },
+    /// Seek each member (by UUID) with the given seek, like `BufferSender::seek()`.
+    Seek(Vec<(Uuid, Option<Box<Seek>>)>)
+}
@@ -0,0 +1,101 @@
+//! Capturing audio from the engine's input channels.
+//!
+//! A `Receiver` (obtained from `EngineContext::new_receiver()`) gets interleaved frames of audio
+//! from a set of input channels, captured by the audio thread into a lock-free ring buffer. To
+//! write input channels straight to disk instead, see `EngineContext::start_recording()`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::atomic::Ordering::*;
+use bounded_spsc_queue::Consumer;
+use uuid::Uuid;
+
+/// Stops a capture on the audio thread when dropped.
+pub(crate) struct CaptureGuard {
+    pub(crate) active: Arc<AtomicBool>,
+    pub(crate) alive: Arc<AtomicBool>
+}
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        self.active.store(false, Relaxed);
+        self.alive.store(false, Relaxed);
+    }
+}
+
+/// Receives audio captured from a set of input channels.
+///
+/// Captured audio is buffered in a ring buffer of `STREAM_BUFFER_SIZE` frames, which you MUST
+/// keep reading from (with `read()`) while the receiver is active - otherwise, it'll fill up, and
+/// audio will be dropped (see `overflowed()`).
+///
+/// Dropping the receiver stops the capture.
+pub struct Receiver {
+    pub(crate) buf: Consumer<f32>,
+    pub(crate) chans: Vec<usize>,
+    pub(crate) overflowed: Arc<AtomicBool>,
+    pub(crate) start_time: Arc<AtomicU64>,
+    pub(crate) frames: Arc<AtomicU64>,
+    pub(crate) uuid: Uuid,
+    pub(crate) guard: CaptureGuard
+}
+impl Receiver {
+    /// Get the input channel numbers this receiver captures, in the order their samples are
+    /// interleaved.
+    pub fn chans(&self) -> &[usize] {
+        &self.chans
+    }
+    /// Set whether this receiver captures audio or not.
+    ///
+    /// Receivers start out inactive.
+    pub fn set_active(&mut self, active: bool) {
+        self.guard.active.store(active, Relaxed);
+    }
+    /// Get whether this receiver captures audio or not.
+    pub fn active(&self) -> bool {
+        self.guard.active.load(Relaxed)
+    }
+    /// Query whether this receiver is alive (i.e. whether the audio thread has accepted it, and
+    /// is still capturing to it).
+    pub fn alive(&self) -> bool {
+        self.guard.alive.load(Relaxed)
+    }
+    /// Get the number of whole frames that are ready to be read.
+    pub fn available(&self) -> usize {
+        self.buf.size() / self.chans.len()
+    }
+    /// Read as many whole frames of interleaved audio as are available and will fit into `out`.
+    ///
+    /// Returns the number of frames read.
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let nchans = self.chans.len();
+        let frames = ::std::cmp::min(out.len() / nchans, self.available());
+        for x in out[..frames * nchans].iter_mut() {
+            *x = self.buf.try_pop().unwrap_or(0.0);
+        }
+        frames
+    }
+    /// Whether audio has had to be dropped, because the receiver's buffer was full.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Relaxed)
+    }
+    /// Get the time (from the engine's clock) that the first frame was captured at, or `None` if
+    /// nothing has been captured yet.
+    ///
+    /// Together with `frames_captured()`, this can be used to work out when any frame was
+    /// captured (for latency measurement, say) - as long as the receiver hasn't been deactivated
+    /// or overflowed in the meantime.
+    pub fn start_time(&self) -> Option<u64> {
+        match self.start_time.load(Relaxed) {
+            ::std::u64::MAX => None,
+            t => Some(t)
+        }
+    }
+    /// Get the number of frames captured so far.
+    pub fn frames_captured(&self) -> u64 {
+        self.frames.load(Relaxed)
+    }
+    /// Get this receiver's UUID.
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+}
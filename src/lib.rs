@@ -8,6 +8,11 @@
 //! "COULD", "POSSIBLE", and "MIGHT" in this document are to be
 //! interpreted as described in [RFC 6919](https://tools.ietf.org/html/rfc6919).
 #![feature(integer_atomics)]
+// These lints suggest things (like `f32::clamp()`, `u64::MAX` and `#[default]`) that this
+// crate's toolchain doesn't have yet - and neither does it have tool lints, hence the `cfg_attr`.
+#![cfg_attr(clippy, allow(clippy::unnecessary_clippy_cfg))]
+#![cfg_attr(clippy, allow(clippy::legacy_numeric_constants, clippy::manual_clamp, clippy::derivable_impls,
+                          clippy::manual_div_ceil, clippy::manual_is_multiple_of))]
 
 pub extern crate sqa_jack;
 extern crate bounded_spsc_queue;
@@ -17,10 +22,23 @@ extern crate failure;
 #[macro_use] extern crate failure_derive;
 extern crate parking_lot;
 extern crate uuid;
+extern crate hound;
 
 pub mod errors;
 pub mod sync;
 pub mod param;
+pub mod backend;
+pub mod offline;
+pub mod bounce;
+pub mod clock;
+pub mod resample;
+pub mod routing;
+pub mod input;
+pub mod bus;
+pub mod stage;
+pub mod meter;
+pub mod group;
+pub mod transaction;
 mod thread;
 
 use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, AtomicPtr};
@@ -28,11 +46,24 @@ use std::sync::atomic::Ordering::*;
 use bounded_spsc_queue::Producer;
 use arrayvec::ArrayVec;
 use std::sync::Arc;
+use std::path::Path;
 use time::Duration;
-use sqa_jack::*;
 pub use errors::EngineResult;
+pub use backend::{Backend, JackBackend};
+pub use input::Receiver;
+pub use bus::Bus;
 use errors::EngineError;
-use param::Parameter;
+use param::{Parameter, FadeDetails};
+use bounce::{BounceSpec, BounceWriter, Bouncer};
+use clock::Clock;
+use resample::{ResampleQuality, Resampler};
+use routing::{Dest, Routing};
+use input::CaptureGuard;
+use stage::{ChannelStage, OutputStage};
+use meter::{MeterBallistics, MeterBank, MeterReading, PlayerMeters};
+use group::{GroupAction, TransportGroup};
+use transaction::Transaction;
+use sync::SharedValue;
 pub use uuid::Uuid;
 pub use sqa_jack as jack;
 /// The maximum amount of streams that can play concurrently.
@@ -49,16 +80,38 @@ pub const MAX_PLAYERS: usize = 512;
 pub const MAX_CHANS: usize = 64;
 #[cfg(feature = "channels-128")]
 pub const MAX_CHANS: usize = 128;
-/// The size of a stream's buffer, in samples.
+/// The size of a stream's buffer, in samples (per channel, for multichannel streams).
 pub const STREAM_BUFFER_SIZE: usize = 100_000;
+/// The maximum amount of channels a single (multichannel) stream can have.
+pub const MAX_SENDER_CHANNELS: usize = 16;
+/// The maximum amount of buses that can be created.
+pub const MAX_BUSES: usize = 64;
+/// The maximum amount of channels a single bus can have.
+pub const MAX_BUS_CHANNELS: usize = 16;
+/// The largest period size (in frames) that buses support.
+///
+/// Buses allocate this many frames of scratch space per channel up front. If the backend ever
+/// runs with larger periods, audio routed to buses will go unheard.
+pub const MAX_BUFFER_SIZE: usize = 8192;
+/// How long the crossfade between the old and new audio is, when a playing stream seeks, in
+/// nanoseconds.
+pub const SEEK_CROSSFADE_TIME: u64 = 10_000_000;
+/// How long the ramp down to silence is, when a stream is stopped with a declick (see
+/// `Sender::set_stop_declick()`), in nanoseconds.
+pub const STOP_DECLICK_TIME: u64 = 5_000_000;
+/// The maximum amount of transactions that can be waiting to be applied at a given time.
+pub const MAX_PENDING_TRANSACTIONS: usize = 64;
 /// The size of the communication buffer between audio thread and main thread, in messages.
 pub const CONTROL_BUFFER_SIZE: usize = MAX_PLAYERS * 2;
+/// The most commands (adding senders, channels and so on) the audio thread handles in one
+/// period, unless changed with `EngineContext::set_command_budget()`.
+pub const DEFAULT_COMMAND_BUDGET: usize = 128;
 /// One second, in nanoseconds.
 pub const ONE_SECOND_IN_NANOSECONDS: u64 = 1_000_000_000;
 
 /// Corresponds to, and controls, a `Player` in the audio thread.
 pub struct Sender<T> {
-    /// Current position, in samples from the start of the buffer (read only)
+    /// Current position, in frames from the start of the buffer (read only)
     position: Arc<AtomicU64>,
     /// Whether this stream will play samples (rw)
     active: Arc<AtomicBool>,
@@ -66,20 +119,47 @@ pub struct Sender<T> {
     alive: Arc<AtomicBool>,
     /// Whether this stream will die when its buffer runs out (rw)
     kill_when_empty: Arc<AtomicBool>,
+    /// Whether this stream is paused (rw)
+    paused: Arc<AtomicBool>,
+    /// The ID of the transport group this stream is in, or 0 (rw)
+    group: Arc<AtomicU64>,
+    /// When the audio thread should stop this stream, or `u64::MAX` for never (rw)
+    stop_time: Arc<AtomicU64>,
+    /// Where (in frames) the audio thread should stop this stream, or `u64::MAX` for never (rw)
+    stop_position: Arc<AtomicU64>,
+    /// Whether the audio thread should kill this stream when it stops it (rw)
+    kill_on_stop: Arc<AtomicBool>,
+    /// Whether the audio thread should ramp this stream down before stopping it (rw)
+    stop_declick: Arc<AtomicBool>,
     /// When (from the system's monotonic clock) the player should begin playback (rw)
     start_time: Arc<AtomicU64>,
-    /// Which channel number this stream is patched to (rw)
-    output_patch: Arc<AtomicUsize>,
+    /// Where each of this stream's channels is sent (rw)
+    routing: Arc<SharedValue<Routing>>,
     /// The playback volume (rw)
-    volume: Arc<AtomicPtr<Parameter<f32>>>,
+    volume: Arc<SharedValue<Parameter<f32>>>,
     /// The master playback volume (rw)
-    master_vol: Arc<AtomicPtr<Parameter<f32>>>,
+    master_vol: Arc<SharedValue<Parameter<f32>>>,
     /// The buffer to write to (or not) - will be a `bounded_spsc_queue::Producer<f32>` or `()`.
     pub buf: T,
     /// The sample rate of this sender. Can differ from the output sample rate.
     pub sample_rate: u64,
+    /// The number of channels in this sender's stream. Samples in its buffer are interleaved.
+    pub channels: usize,
+    /// A seek waiting for the audio thread to pick it up, or null (rw)
+    seek: Arc<AtomicPtr<thread::Seek>>,
+    /// A loop region waiting for the audio thread to pick it up, or null (rw)
+    loop_pending: Arc<AtomicPtr<thread::Loop>>,
+    /// Whether to leave the loop region the next time its end is reached (rw)
+    devamp: Arc<AtomicBool>,
+    /// How this stream is resampled, and the sample rate it's resampled to.
+    quality: ResampleQuality,
+    output_rate: u64,
     /// Whether this sender was the original, or a clone.
     original: bool,
+    /// The engine's clock.
+    clock: Arc<dyn Clock>,
+    /// The stream's level meters (rw)
+    meters: Arc<PlayerMeters>,
     /// The UUID of this sender.
     uuid: Uuid
 }
@@ -102,11 +182,44 @@ impl<T> Sender<T> {
     pub fn set_active(&mut self, active: bool) {
         self.active.store(active, Relaxed);
     }
+    /// Pause the stream, holding its position until `resume()` is called.
+    ///
+    /// Unlike `set_active(false)`, this keeps the stream's start time and position in step: on
+    /// resuming, the audio thread moves the start time forward by however long the stream was
+    /// paused for, so playback carries on from exactly where it left off.
+    pub fn pause(&mut self) {
+        self.paused.store(true, Relaxed);
+    }
+    /// Resume the stream after a `pause()`, from the next period onwards.
+    pub fn resume(&mut self) {
+        self.paused.store(false, Relaxed);
+    }
+    /// Query whether this stream is paused.
+    ///
+    /// A paused stream can still be `active` - it'll start playing again as soon as it's resumed.
+    pub fn paused(&self) -> bool {
+        self.paused.load(Relaxed)
+    }
+    /// Enrol this stream in a transport group, or take it out of the one it's in (with `None`).
+    ///
+    /// A stream can only be in one group at a time.
+    pub fn set_group(&mut self, group: Option<TransportGroup>) {
+        self.group.store(group.map(|g| g.id).unwrap_or(0), Relaxed);
+    }
+    /// Get the transport group this stream is in, if any.
+    pub fn group(&self) -> Option<TransportGroup> {
+        match self.group.load(Relaxed) {
+            0 => None,
+            id => Some(TransportGroup { id })
+        }
+    }
     /// Start playing the stream, from this moment on.
     ///
-    /// This calls `set_start_time()` with the current time, and calls `set_active(true)`.
+    /// This calls `set_start_time()` with the current time, and calls `set_active(true)`. To carry
+    /// on from where a stream left off, use `pause()` and `resume()` instead.
     pub fn unpause(&mut self) {
-        self.set_start_time(time::precise_time_ns());
+        let now = self.now();
+        self.set_start_time(now);
         self.set_active(true);
     }
     /// Start playing the stream, as if it was supposed to start at a given time.
@@ -116,39 +229,29 @@ impl<T> Sender<T> {
         self.set_start_time(time);
         self.set_active(true);
     }
+    /// Set the master volume of this stream (which is shared with any streams made from it
+    /// with `EngineContext::new_sender_with_master()`).
+    ///
+    /// As with `set_volume()`, the old volume is sent back in a `VolumeRemoved` message.
     pub fn set_master_volume(&mut self, vol: Box<Parameter<f32>>) {
-        let val = Box::into_raw(vol);
-        let old_ptr = self.master_vol.swap(val, AcqRel);
-        unsafe {
-            let _: Box<Parameter<f32>> = Box::from_raw(old_ptr);
-        }
+        self.master_vol.set(vol);
     }
+    /// Get the master volume of this stream, as most recently set.
     pub fn master_volume(&self) -> Parameter<f32> {
-        let ret;
-        unsafe {
-            let val = self.master_vol.load(Acquire);
-            ret = (*val).clone();
-            self.master_vol.store(val, Release);
-        }
-        ret
+        self.master_vol.get()
     }
     /// Set the volume of this stream.
+    ///
+    /// The audio thread picks the new volume up at the start of its next period, and sends the
+    /// old one back in a `VolumeRemoved` message to be deallocated.
     pub fn set_volume(&mut self, vol: Box<Parameter<f32>>) {
-        let val = Box::into_raw(vol);
-        let old_ptr = self.volume.swap(val, AcqRel);
-        unsafe {
-            let _: Box<Parameter<f32>> = Box::from_raw(old_ptr);
-        }
+        self.volume.set(vol);
     }
     /// Get the volume of this stream.
+    ///
+    /// This is the volume most recently set, which the audio thread might not have picked up yet.
     pub fn volume(&self) -> Parameter<f32> {
-        let ret;
-        unsafe {
-            let val = self.volume.load(Acquire);
-            ret = (*val).clone();
-            self.volume.store(val, Release);
-        }
-        ret
+        self.volume.get()
     }
     /// Get whether this stream will play samples or not.
     pub fn active(&self) -> bool {
@@ -164,10 +267,11 @@ impl<T> Sender<T> {
     /// This will also reset its `start_time` to the current time as a preventative measure against calling this function without doing so
     /// while the stream is playing.
     pub fn reset_position(&mut self) {
-        self.set_start_time(time::precise_time_ns());
+        let now = self.now();
+        self.set_start_time(now);
         self.position.store(0, Relaxed);
     }
-    /// Get the stream's position in samples.
+    /// Get the stream's position in frames (samples per channel).
     ///
     /// This position starts at 0 when the stream starts, and is incremented every time the stream delivers samples.
     /// It is compared to the `start_time`, meaning that you MUST NOT change one without changing the other (otherwise, the stream will
@@ -179,20 +283,159 @@ impl<T> Sender<T> {
     pub fn position(&self) -> Duration {
         Duration::milliseconds(((self.position.load(Relaxed) as f64 / self.sample_rate as f64) * 1000.0)as i64)
     }
+    /// Get this stream's routing matrix.
+    ///
+    /// This is the matrix most recently set, which the audio thread might not have picked up yet.
+    pub fn routing(&self) -> Routing {
+        self.routing.get()
+    }
+    /// Set this stream's routing matrix, replacing the old one.
+    ///
+    /// The audio thread picks the new matrix up at the start of its next period, and sends the
+    /// old one back in a `RoutingRemoved` message to be deallocated.
+    ///
+    /// A crosspoint to an invalid channel number (or from a channel the stream doesn't have)
+    /// will cause the stream to deactivate (`active` will be set to false).
+    pub fn set_routing(&mut self, routing: Routing) {
+        self.routing.set(Box::new(routing));
+    }
+    /// Send one of this stream's channels to a destination at a given gain, leaving the
+    /// rest of the routing matrix untouched.
+    pub fn set_crosspoint<D: Into<Dest>>(&mut self, source: usize, dest: D, gain: f32) {
+        let mut routing = self.routing();
+        routing.set(source, dest, gain);
+        self.set_routing(routing);
+    }
+    /// Stop sending one of this stream's channels to a destination.
+    pub fn remove_crosspoint<D: Into<Dest>>(&mut self, source: usize, dest: D) {
+        let mut routing = self.routing();
+        if routing.remove(source, dest) {
+            self.set_routing(routing);
+        }
+    }
     /// Get this stream's output patch (which channel number this stream is patched to)
+    ///
+    /// For multichannel streams, this is the output patch of the first channel. If the channel is
+    /// sent to more than one output channel, this is the first one it was sent to; if it isn't
+    /// sent to any output channels (ignoring buses), this is `usize::MAX`.
     pub fn output_patch(&self) -> usize {
-        self.output_patch.load(Relaxed)
+        self.output_patch_for(0).unwrap_or(::std::usize::MAX)
     }
-    /// Set this stream's output patch (which channel number this stream is patched to)
+    /// Set this stream's output patch (which channel number this stream is patched to), at
+    /// unity gain, replacing any other crosspoints from the stream's (first) channel.
     ///
     /// An invalid output patch will cause the stream to deactivate (`active` will be set to false).
     pub fn set_output_patch(&mut self, patch: usize) {
-        self.output_patch.store(patch, Relaxed);
+        let mut routing = self.routing();
+        routing.clear_source(0);
+        routing.set(0, patch, 1.0);
+        self.set_routing(routing);
     }
-    /// Set this stream's start time - the time, from the system's monotonic clock, that it starts playing at.
+    /// Get the output patch of one of this stream's channels, as with `output_patch()`.
+    ///
+    /// Returns `None` if the channel isn't sent anywhere, or the stream doesn't have that many
+    /// channels.
+    pub fn output_patch_for(&self, ch: usize) -> Option<usize> {
+        self.routing().dests(ch)
+            .filter_map(|d| match d {
+                Dest::Channel(c) => Some(c),
+                _ => None
+            })
+            .next()
+    }
+    /// Set the output patch of one of this stream's channels, as with `set_output_patch()`.
+    pub fn set_output_patch_for(&mut self, ch: usize, patch: usize) -> EngineResult<()> {
+        if ch >= self.channels {
+            Err(EngineError::NoSuchChannel)?
+        }
+        let mut routing = self.routing();
+        routing.clear_source(ch);
+        routing.set(ch, patch, 1.0);
+        self.set_routing(routing);
+        Ok(())
+    }
+    /// Have the audio thread stop this stream at a given time (from the engine's clock).
+    ///
+    /// Output stops at exactly the sample nearest to that time (ramping down to it first, if
+    /// `set_stop_declick()` is on). If `kill` is true, the stream is then killed (`alive` will
+    /// be set to false, as if the original sender had been dropped); otherwise, it's just
+    /// deactivated. Either way, the audio thread sends a `PlayerStopped` message when it does
+    /// this.
+    ///
+    /// This replaces any stop that was already scheduled.
+    pub fn stop_at(&mut self, time: u64, kill: bool) {
+        self.kill_on_stop.store(kill, Relaxed);
+        self.stop_position.store(::std::u64::MAX, Relaxed);
+        self.stop_time.store(time, Relaxed);
+    }
+    /// Have the audio thread stop this stream once its position (see `position_samples()`)
+    /// reaches a given frame, like `stop_at()`.
+    ///
+    /// Positions count every frame played, so a stream with a loop region set (see
+    /// `BufferSender::set_loop()`) counts the loop region as many times as it plays it.
+    pub fn stop_at_position(&mut self, pos: u64, kill: bool) {
+        self.kill_on_stop.store(kill, Relaxed);
+        self.stop_time.store(::std::u64::MAX, Relaxed);
+        self.stop_position.store(pos, Relaxed);
+    }
+    /// Set whether this stream is ramped down to silence over `STOP_DECLICK_TIME` before it's
+    /// stopped with `stop_at()` or `stop_at_position()`, so that it doesn't click.
+    ///
+    /// This is off by default.
+    pub fn set_stop_declick(&mut self, declick: bool) {
+        self.stop_declick.store(declick, Relaxed);
+    }
+    /// Get whether this stream is ramped down before it's stopped.
+    pub fn stop_declick(&self) -> bool {
+        self.stop_declick.load(Relaxed)
+    }
+    /// Fade this stream's volume out to silence over a given duration, starting now, and have
+    /// the audio thread stop it (see `stop_at()`) as soon as the fade is complete.
+    ///
+    /// The fade starts from the volume the stream is at right now, and replaces its volume
+    /// parameter - so the volume MUST be set again before the stream is reactivated, or it'll
+    /// be silent.
+    pub fn fade_out_and_stop(&mut self, dur: ::std::time::Duration, kill: bool) {
+        let now = self.now();
+        let mut fd = FadeDetails::new(self.volume().get(now), 0.0);
+        fd.set_duration(dur);
+        fd.set_start_time(now);
+        fd.set_active(true);
+        let end = now + fd.duration_nanos();
+        self.set_volume(Box::new(Parameter::Fade(fd)));
+        self.stop_at(end, kill);
+    }
+    /// Cancel a stop scheduled with `stop_at()`, `stop_at_position()` or
+    /// `fade_out_and_stop()`, if it hasn't happened yet.
+    ///
+    /// This doesn't undo the fade started by `fade_out_and_stop()`.
+    pub fn cancel_stop(&mut self) {
+        self.stop_time.store(::std::u64::MAX, Relaxed);
+        self.stop_position.store(::std::u64::MAX, Relaxed);
+    }
+    /// Get the time the audio thread is going to stop this stream at, if it's going to.
+    pub fn stop_time(&self) -> Option<u64> {
+        match self.stop_time.load(Relaxed) {
+            ::std::u64::MAX => None,
+            t => Some(t)
+        }
+    }
+    /// Get the position the audio thread is going to stop this stream at, if it's going to.
+    pub fn stop_position(&self) -> Option<u64> {
+        match self.stop_position.load(Relaxed) {
+            ::std::u64::MAX => None,
+            p => Some(p)
+        }
+    }
+    /// Leave the loop region (see `BufferSender::set_loop()`) the next time its end is reached,
+    /// carrying on with the rest of the stream.
+    pub fn devamp(&mut self) {
+        self.devamp.store(true, Relaxed);
+    }
+    /// Set this stream's start time - the time, from the engine's clock, that it starts playing at.
     ///
     /// The stream will maintain its playback position relative to this start time, skipping frames as needed to catch up.
-    /// To get the current time from the system's monotonic clock, call `Sender::precise_time_ns`.
+    /// To get the current time from the engine's clock, call `Sender::now`.
     pub fn set_start_time(&mut self, st: u64) {
         self.start_time.store(st, Relaxed);
     }
@@ -203,26 +446,151 @@ impl<T> Sender<T> {
             active: self.active.clone(),
             alive: self.alive.clone(),
             start_time: self.start_time.clone(),
-            output_patch: self.output_patch.clone(),
+            routing: self.routing.clone(),
             volume: self.volume.clone(),
             master_vol: self.master_vol.clone(),
             kill_when_empty: self.kill_when_empty.clone(),
+            paused: self.paused.clone(),
+            group: self.group.clone(),
+            stop_time: self.stop_time.clone(),
+            stop_position: self.stop_position.clone(),
+            kill_on_stop: self.kill_on_stop.clone(),
+            stop_declick: self.stop_declick.clone(),
             buf: (),
             sample_rate: self.sample_rate,
+            channels: self.channels,
+            seek: self.seek.clone(),
+            loop_pending: self.loop_pending.clone(),
+            devamp: self.devamp.clone(),
+            quality: self.quality,
+            output_rate: self.output_rate,
             original: false,
+            clock: self.clock.clone(),
+            meters: self.meters.clone(),
             uuid: self.uuid
         }
     }
+    /// Set whether the audio thread meters this stream's levels.
+    ///
+    /// Metering is off by default, to save a bit of work on the audio thread.
+    pub fn set_metering(&mut self, metering: bool) {
+        self.meters.enabled.store(metering, Relaxed);
+    }
+    /// Get whether the audio thread meters this stream's levels.
+    pub fn metering(&self) -> bool {
+        self.meters.enabled.load(Relaxed)
+    }
+    /// Take a reading of the level of one of this stream's channels (after its volume has been
+    /// applied, but before routing).
+    ///
+    /// Returns `None` if the stream doesn't have that many channels. Readings don't change
+    /// while metering is off.
+    pub fn meter(&self, ch: usize) -> Option<MeterReading> {
+        self.meters.meters.get(ch).map(|m| m.reading())
+    }
     /// Get this sender's UUID.
     pub fn uuid(&self) -> Uuid {
         self.uuid
     }
+    /// Get the current time from the engine's clock.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
     /// A wrapper around `time::precise_time_ns()`.
+    ///
+    /// This reads the system's monotonic clock, which is only the engine's clock if the engine
+    /// was set up with a `MonotonicClock`. You probably want `Sender::now` instead.
     #[inline(always)]
     pub fn precise_time_ns() -> u64 {
         time::precise_time_ns()
     }
 }
+impl Sender<Producer<f32>> {
+    /// Seek to a given position in the stream, in frames (samples per channel).
+    ///
+    /// This replaces the stream's buffer with a new, empty one, which you SHOULD refill (starting
+    /// from the frame at `pos`) straight away. On its next period, the audio thread swaps the new
+    /// buffer in - throwing away whatever was queued in the old one - and sets the stream's
+    /// position and start time to match, sending a `PlayerSeeked` message when it does. If the
+    /// stream is playing, the old audio is crossfaded into the new over `SEEK_CROSSFADE_TIME`
+    /// (or less, if the old audio was about to go into a loop region, and not at all if it was
+    /// already in one).
+    ///
+    /// If the new buffer is still empty when the audio thread picks it up, the stream will
+    /// behave as if its buffer ran out (which will kill it, if `kill_when_empty` is set).
+    /// If the stream hasn't reached its start time yet, it'll start from `pos` at that time,
+    /// but its position will count up from 0 as usual.
+    pub fn seek_samples(&mut self, pos: u64) {
+        let seek = self.make_seek(pos);
+        let old_ptr = self.seek.swap(Box::into_raw(seek), AcqRel);
+        if !old_ptr.is_null() {
+            unsafe {
+                let _: Box<thread::Seek> = Box::from_raw(old_ptr);
+            }
+        }
+    }
+    /// Replace this sender's buffer with a new one, returning a seek to hand to the audio thread.
+    fn make_seek(&mut self, pos: u64) -> Box<thread::Seek> {
+        let (p, c) = bounded_spsc_queue::make(STREAM_BUFFER_SIZE * self.channels);
+        self.buf = p;
+        Box::new(thread::Seek {
+            buf: c,
+            resampler: Resampler::new(self.quality, self.sample_rate, self.output_rate, self.channels),
+            position: pos,
+            xfade_len: 0,
+            xfade_left: 0
+        })
+    }
+    /// Set up a loop region, replacing any existing one.
+    ///
+    /// `region` holds the audio in the loop region, as interleaved frames, and `start` is the
+    /// frame of the stream it starts at. When playback gets to `start`, the audio thread plays
+    /// `region`, jumping back to the start of it `loops` more times (or forever, if `loops` is
+    /// `None`), or until `devamp()` is called - and then carries on from the buffer. The jumps
+    /// are sample-accurate, and each one is reported with a `PlayerLooped` message.
+    ///
+    /// The audio in the loop region MUST NOT be pushed to the buffer: push the frames up to
+    /// `start`, and then carry on from the frame after the end of the region.
+    ///
+    /// If playback is already past `start` when the audio thread picks the loop up, it's sent
+    /// back with a `LoopRemoved` message, unplayed. Seeking rewinds the loop, if the new
+    /// position is before its start, or skips it otherwise.
+    ///
+    /// Returns `EngineError::InvalidLoop` if `region` is empty or isn't made up of whole frames.
+    pub fn set_loop(&mut self, start: u64, region: Vec<f32>, loops: Option<u32>) -> EngineResult<()> {
+        if region.is_empty() || region.len() % self.channels != 0 {
+            Err(EngineError::InvalidLoop)?
+        }
+        let loops = loops.map(|l| ::std::cmp::min(l, ::std::u32::MAX - 1)).unwrap_or(::std::u32::MAX);
+        let lp = Box::new(thread::Loop {
+            buf: region.into_boxed_slice(),
+            start,
+            loops,
+            loops_left: loops,
+            wraps: 0,
+            wraps_sent: 0,
+            entered: false,
+            cursor: 0
+        });
+        self.devamp.store(false, Relaxed);
+        let old_ptr = self.loop_pending.swap(Box::into_raw(lp), AcqRel);
+        if !old_ptr.is_null() {
+            unsafe {
+                let _: Box<thread::Loop> = Box::from_raw(old_ptr);
+            }
+        }
+        Ok(())
+    }
+    /// Seek to a given position in the stream (see `seek_samples()`).
+    ///
+    /// Returns the frame the buffer MUST be refilled from.
+    pub fn seek(&mut self, pos: Duration) -> u64 {
+        let secs = pos.num_nanoseconds().unwrap_or(::std::i64::MAX).max(0) as f64 / ONE_SECOND_IN_NANOSECONDS as f64;
+        let frames = (secs * self.sample_rate as f64).round() as u64;
+        self.seek_samples(frames);
+        frames
+    }
+}
 impl<T> Drop for Sender<T> {
     /// If this sender was the original: deactivates the stream, setting `alive` to false.
     fn drop(&mut self) {
@@ -232,43 +600,83 @@ impl<T> Drop for Sender<T> {
         }
     }
 }
-/// Main engine context, containing a connection to JACK.
-pub struct EngineContext {
-    pub conn: JackConnection<Activated>,
-    pub chans: ArrayVec<[Option<JackPort>; MAX_CHANS]>,
+/// Main engine context, containing an audio backend (a connection to JACK, by default).
+pub struct EngineContext<B = JackBackend> where B: Backend {
+    pub backend: B,
+    pub chans: ArrayVec<[Option<B::Port>; MAX_CHANS]>,
     pub holes: ArrayVec<[usize; MAX_CHANS]>,
+    pub inputs: ArrayVec<[Option<B::Port>; MAX_CHANS]>,
+    pub input_holes: ArrayVec<[usize; MAX_CHANS]>,
+    pub buses: ArrayVec<[Option<Bus>; MAX_BUSES]>,
+    pub bus_holes: ArrayVec<[usize; MAX_BUSES]>,
+    clock: Arc<dyn Clock>,
+    meters: Arc<MeterBank>,
+    next_group: u64,
     length: Arc<AtomicUsize>,
-    control: Producer<thread::AudioThreadCommand>,
+    command_budget: Arc<AtomicUsize>,
+    control: Producer<thread::AudioThreadCommand<B::Port>>,
     rx: Option<sync::AudioThreadHandle>
 }
-impl EngineContext {
+impl EngineContext<JackBackend> {
     /// Initialise the SQA Engine, opening a connection to JACK and starting the audio thread.
     ///
     /// The connection is made under a given name if provided, otherwise under "SQA Engine".
     pub fn new(name: Option<&str>) -> EngineResult<Self> {
+        let backend = JackBackend::new(name.unwrap_or("SQA Engine"))?;
+        Self::with_backend(backend)
+    }
+}
+impl<B> EngineContext<B> where B: Backend {
+    /// Initialise the SQA Engine on a given backend, starting the audio thread.
+    ///
+    /// The engine will use the backend's default clock.
+    pub fn with_backend(backend: B) -> EngineResult<Self> {
+        let clock = backend.default_clock();
+        Self::with_backend_and_clock(backend, clock)
+    }
+    /// Initialise the SQA Engine on a given backend, using a given clock for all timing.
+    ///
+    /// Some backends only work with their own clock, and return an error if given any other
+    /// (see `OfflineBackend`).
+    pub fn with_backend_and_clock(mut backend: B, clock: Arc<dyn Clock>) -> EngineResult<Self> {
         let len = Arc::new(AtomicUsize::new(0));
+        let budget = Arc::new(AtomicUsize::new(DEFAULT_COMMAND_BUDGET));
         let (p, c) = bounded_spsc_queue::make(CONTROL_BUFFER_SIZE);
         let (rc, rp) = unsafe { sync::AudioThreadHandle::make() };
-        let mut conn = JackConnection::connect(name.unwrap_or("SQA Engine"), Some(OPEN_NO_START_SERVER))?;
+        let meters = Arc::new(MeterBank::new(MAX_CHANS));
         let dctx = thread::DeviceContext {
             players: ArrayVec::new(),
             chans: ArrayVec::new(),
             holes: ArrayVec::new(),
+            inputs: ArrayVec::new(),
+            input_holes: ArrayVec::new(),
+            captures: ArrayVec::new(),
+            buses: ArrayVec::new(),
+            bus_holes: ArrayVec::new(),
+            pending: ArrayVec::new(),
             control: c,
             length: len.clone(),
-            sample_rate: conn.sample_rate() as u64,
+            command_budget: budget.clone(),
+            tap: None,
+            clock: clock.clone(),
+            sample_rate: backend.sample_rate(),
+            meters: meters.clone(),
             sender: rp
         };
-        conn.set_handler(dctx)?;
-        let conn = match conn.activate() {
-            Ok(c) => c,
-            Err((_, err)) => return Err(err.into())
-        };
+        backend.start(dctx)?;
         Ok(EngineContext {
-            conn: conn,
+            backend,
+            clock,
+            meters,
+            next_group: 1,
             chans: ArrayVec::new(),
             holes: ArrayVec::new(),
+            inputs: ArrayVec::new(),
+            input_holes: ArrayVec::new(),
+            buses: ArrayVec::new(),
+            bus_holes: ArrayVec::new(),
             length: len,
+            command_budget: budget,
             control: p,
             rx: Some(rc)
         })
@@ -289,22 +697,44 @@ impl EngineContext {
     pub fn num_senders(&self) -> usize {
         self.length.load(Relaxed)
     }
+    /// Set the most commands the audio thread will handle in one period (at least one always is).
+    ///
+    /// Almost everything done with an `EngineContext` (adding senders and channels, submitting
+    /// transactions, and so on) is a command to the audio thread, and is done at the start of the
+    /// next period - unless there are more commands waiting than the budget allows, in which case
+    /// the rest wait for later periods, and a `CommandBudgetExceeded` message is sent. Lower
+    /// budgets spread the work out, at the cost of more latency when lots is done at once.
+    pub fn set_command_budget(&mut self, budget: usize) {
+        self.command_budget.store(budget, Relaxed);
+    }
+    /// Get the most commands the audio thread will handle in one period.
+    pub fn command_budget(&self) -> usize {
+        self.command_budget.load(Relaxed)
+    }
+    /// Get the engine's clock.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+    /// Get the current time from the engine's clock.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
     pub fn new_channel(&mut self, name: &str) -> EngineResult<usize> {
         /* NOTE: This code must mirror the code in thread.rs */
-        let port = self.conn.register_port(name, PORT_IS_OUTPUT | PORT_IS_TERMINAL)?;
+        let port = self.backend.register_port(name)?;
         if (self.chans.len() - self.holes.len()) == self.chans.capacity() - 1 {
             Err(EngineError::LimitExceeded)?
         }
         let ret;
         if let Some(ix) = self.holes.remove(0) {
-            self.chans[ix] = Some(port);
+            self.chans[ix] = Some(port.clone());
             ret = ix;
         }
         else {
             ret = self.chans.len();
-            self.chans.push(Some(port));
+            self.chans.push(Some(port.clone()));
         }
-        self.control.push(thread::AudioThreadCommand::AddChannel(port.clone()));
+        self.control.push(thread::AudioThreadCommand::AddChannel(port));
         Ok(ret)
     }
     pub fn remove_channel(&mut self, idx: usize) -> EngineResult<()> {
@@ -315,60 +745,410 @@ impl EngineContext {
         self.chans.push(None);
         self.holes.push(idx);
         self.control.push(thread::AudioThreadCommand::RemoveChannel(idx));
-        self.conn.unregister_port(self.chans.swap_remove(idx).unwrap().unwrap())?;
+        self.backend.unregister_port(self.chans.swap_remove(idx).unwrap().unwrap())?;
         Ok(())
     }
-    pub fn new_sender(&mut self, sample_rate: u64) -> BufferSender {
-        self.new_sender_ext(sample_rate, None)
+    /// Set the output stage a channel's audio goes through, once it's been mixed.
+    ///
+    /// Channels start out with `OutputStage::None`. The old stage is sent back in an
+    /// `OutputStageRemoved` message.
+    pub fn set_output_stage(&mut self, idx: usize, stage: OutputStage) -> EngineResult<()> {
+        if idx >= self.chans.len() || self.chans[idx].is_none() {
+            Err(EngineError::NoSuchChannel)?
+        }
+        let stage = ChannelStage::new(stage, self.backend.sample_rate());
+        self.control.push(thread::AudioThreadCommand::SetOutputStage(idx, stage));
+        Ok(())
     }
-    pub fn new_sender_with_master<T>(&mut self, master: &Sender<T>) -> BufferSender {
-        let master_vol = master.master_vol.clone();
-        self.new_sender_ext(master.sample_rate, Some(master_vol))
+    /// Take a reading of the level of a channel (after its output stage).
+    ///
+    /// Returns `None` if the channel doesn't exist.
+    pub fn channel_meter(&self, idx: usize) -> Option<MeterReading> {
+        match self.chans.get(idx) {
+            Some(&Some(_)) => Some(self.meters.chans[idx].reading()),
+            _ => None
+        }
+    }
+    /// Set how all meters (for channels and streams) respond to changes in level.
+    pub fn set_meter_ballistics(&mut self, ballistics: MeterBallistics) {
+        self.meters.ballistics.set(ballistics);
     }
-    fn new_sender_ext(&mut self, sample_rate: u64, master_vol: Option<Arc<AtomicPtr<Parameter<f32>>>>) -> BufferSender {
-        let (p, c) = bounded_spsc_queue::make(STREAM_BUFFER_SIZE);
+    /// Get how all meters respond to changes in level.
+    pub fn meter_ballistics(&self) -> MeterBallistics {
+        self.meters.ballistics.get()
+    }
+    /// Connect a channel to another port (which may not belong to this engine), given its name.
+    pub fn connect_channel(&mut self, idx: usize, to: &str) -> EngineResult<()> {
+        match self.chans.get(idx) {
+            Some(Some(port)) => self.backend.connect_port(port, to),
+            _ => Err(EngineError::NoSuchChannel)
+        }
+    }
+    /// Disconnect a channel from another port, given its name.
+    pub fn disconnect_channel(&mut self, idx: usize, from: &str) -> EngineResult<()> {
+        match self.chans.get(idx) {
+            Some(Some(port)) => self.backend.disconnect_port(port, from),
+            _ => Err(EngineError::NoSuchChannel)
+        }
+    }
+    /// Make a new bus with a given number of channels, returning its bus number.
+    ///
+    /// The bus starts out at unity gain, and isn't routed anywhere.
+    pub fn new_bus(&mut self, channels: usize) -> EngineResult<usize> {
+        /* NOTE: This code must mirror the code in thread.rs */
+        if channels == 0 || channels > MAX_BUS_CHANNELS {
+            Err(EngineError::LimitExceeded)?
+        }
+        if (self.buses.len() - self.bus_holes.len()) == self.buses.capacity() - 1 {
+            Err(EngineError::LimitExceeded)?
+        }
+        let ret = match self.bus_holes.first() {
+            Some(&ix) => ix,
+            None => self.buses.len()
+        };
+        let volume = Arc::new(SharedValue::new(Parameter::Raw(1.0)));
+        let routing = Arc::new(SharedValue::new(Routing::new()));
+        let bus = Bus {
+            idx: ret,
+            channels,
+            volume: volume.clone(),
+            routing: routing.clone()
+        };
+        if let Some(ix) = self.bus_holes.remove(0) {
+            self.buses[ix] = Some(bus);
+        }
+        else {
+            self.buses.push(Some(bus));
+        }
+        self.control.push(thread::AudioThreadCommand::AddBus(thread::DeviceBus {
+            channels,
+            buf: vec![0.0; channels * MAX_BUFFER_SIZE].into_boxed_slice(),
+            volume,
+            routing
+        }));
+        Ok(ret)
+    }
+    /// Make a new, empty transaction, to batch up changes to apply all at once.
+    pub fn new_transaction(&self) -> Transaction {
+        Transaction::new(self.backend.sample_rate())
+    }
+    /// Submit a transaction to the audio thread, which will apply all of its changes at the
+    /// start of one period (see `Transaction`).
+    pub fn submit(&mut self, tx: Transaction) {
+        tx.update_copies();
+        self.control.push(thread::AudioThreadCommand::Transaction(tx));
+    }
+    /// Make a new transport group. Enrol streams in it with `Sender::set_group()`.
+    pub fn new_group(&mut self) -> TransportGroup {
+        let ret = TransportGroup { id: self.next_group };
+        self.next_group += 1;
+        ret
+    }
+    /// Start every stream in a transport group playing at a given time (as with
+    /// `Sender::play_from_time()`), all in the same period.
+    pub fn group_start(&mut self, group: TransportGroup, time: u64) {
+        self.control.push(thread::AudioThreadCommand::GroupTransport(group.id, GroupAction::Start(time)));
+    }
+    /// Pause every stream in a transport group (as with `Sender::pause()`), all in the same period.
+    pub fn group_pause(&mut self, group: TransportGroup) {
+        self.control.push(thread::AudioThreadCommand::GroupTransport(group.id, GroupAction::Pause));
+    }
+    /// Resume every stream in a transport group (as with `Sender::resume()`), all in the same period.
+    pub fn group_resume(&mut self, group: TransportGroup) {
+        self.control.push(thread::AudioThreadCommand::GroupTransport(group.id, GroupAction::Resume));
+    }
+    /// Stop every stream in a transport group at a given time (as with `Sender::stop_at()`).
+    pub fn group_stop(&mut self, group: TransportGroup, time: u64, kill: bool) {
+        self.control.push(thread::AudioThreadCommand::GroupTransport(group.id, GroupAction::Stop { time, kill }));
+    }
+    /// Seek every stream in a transport group to a given position, in frames (as with
+    /// `BufferSender::seek_samples()`), all in the same period.
+    ///
+    /// `members` MUST contain the senders for every stream in the group; as with
+    /// `seek_samples()`, their buffers are replaced, and you SHOULD refill them straight away.
+    /// Senders in `members` that aren't in the group are left alone.
+    /// A `GroupSeeked` message is sent once the seek has happened.
+    pub fn group_seek<'a, I>(&mut self, group: TransportGroup, members: I, pos: u64) where I: IntoIterator<Item=&'a mut BufferSender> {
+        let seeks = members.into_iter()
+            .filter(|s| s.group() == Some(group))
+            .map(|s| (s.uuid, Some(s.make_seek(pos))))
+            .collect();
+        self.control.push(thread::AudioThreadCommand::GroupTransport(group.id, GroupAction::Seek(seeks)));
+    }
+    pub fn remove_bus(&mut self, idx: usize) -> EngineResult<()> {
+        /* NOTE: This code must mirror the code in thread.rs */
+        if idx >= self.buses.len() || self.bus_holes.contains(&idx) {
+            Err(EngineError::NoSuchChannel)?
+        }
+        self.buses.push(None);
+        self.bus_holes.push(idx);
+        self.control.push(thread::AudioThreadCommand::RemoveBus(idx));
+        self.buses.swap_remove(idx);
+        Ok(())
+    }
+    /// Get the `Bus` with a given bus number, to change its volume and routing.
+    pub fn bus(&mut self, idx: usize) -> Option<&mut Bus> {
+        match self.buses.get_mut(idx) {
+            Some(&mut Some(ref mut bus)) => Some(bus),
+            _ => None
+        }
+    }
+    /// Make a new input channel, capturing audio from the backend under a given name.
+    pub fn new_input_channel(&mut self, name: &str) -> EngineResult<usize> {
+        /* NOTE: This code must mirror the code in thread.rs */
+        if (self.inputs.len() - self.input_holes.len()) == self.inputs.capacity() - 1 {
+            Err(EngineError::LimitExceeded)?
+        }
+        let port = self.backend.register_input_port(name)?;
+        let ret;
+        if let Some(ix) = self.input_holes.remove(0) {
+            self.inputs[ix] = Some(port.clone());
+            ret = ix;
+        }
+        else {
+            ret = self.inputs.len();
+            self.inputs.push(Some(port.clone()));
+        }
+        self.control.push(thread::AudioThreadCommand::AddInput(port));
+        Ok(ret)
+    }
+    pub fn remove_input_channel(&mut self, idx: usize) -> EngineResult<()> {
+        /* NOTE: This code must mirror the code in thread.rs */
+        if idx >= self.inputs.len() || self.input_holes.contains(&idx) {
+            Err(EngineError::NoSuchChannel)?
+        }
+        self.inputs.push(None);
+        self.input_holes.push(idx);
+        self.control.push(thread::AudioThreadCommand::RemoveInput(idx));
+        self.backend.unregister_port(self.inputs.swap_remove(idx).unwrap().unwrap())?;
+        Ok(())
+    }
+    /// Connect an input channel to another port (which may not belong to this engine), given
+    /// its name, so that the input channel captures that port's audio.
+    pub fn connect_input_channel(&mut self, idx: usize, from: &str) -> EngineResult<()> {
+        match self.inputs.get(idx) {
+            Some(Some(port)) => self.backend.connect_port(port, from),
+            _ => Err(EngineError::NoSuchChannel)
+        }
+    }
+    /// Disconnect an input channel from another port, given its name.
+    pub fn disconnect_input_channel(&mut self, idx: usize, from: &str) -> EngineResult<()> {
+        match self.inputs.get(idx) {
+            Some(Some(port)) => self.backend.disconnect_port(port, from),
+            _ => Err(EngineError::NoSuchChannel)
+        }
+    }
+    /// Make a new receiver, capturing audio from a set of input channels (interleaved in the
+    /// order given).
+    ///
+    /// The receiver starts out inactive - call `Receiver::set_active(true)` to start capturing.
+    pub fn new_receiver(&mut self, inputs: &[usize]) -> EngineResult<Receiver> {
+        let mut chans = ArrayVec::new();
+        for &ch in inputs {
+            if ch >= self.inputs.len() || self.inputs[ch].is_none() {
+                Err(EngineError::NoSuchChannel)?
+            }
+            if chans.push(ch).is_some() {
+                Err(EngineError::LimitExceeded)?
+            }
+        }
+        if chans.is_empty() {
+            Err(EngineError::NoSuchChannel)?
+        }
+        let (p, c) = bounded_spsc_queue::make(STREAM_BUFFER_SIZE * chans.len());
         let active = Arc::new(AtomicBool::new(false));
         let alive = Arc::new(AtomicBool::new(false));
-        let kill_when_empty = Arc::new(AtomicBool::new(false));
-        let position = Arc::new(AtomicU64::new(0));
-        let start_time = Arc::new(AtomicU64::new(0));
-        let default_volume = Box::new(Parameter::Raw(1.0));
-        let default_master_vol = default_volume.clone();
-        let volume = Arc::new(AtomicPtr::new(Box::into_raw(default_volume)));
-        let master_vol = master_vol.unwrap_or(
-            Arc::new(AtomicPtr::new(Box::into_raw(default_master_vol))));
-        let output_patch = Arc::new(AtomicUsize::new(::std::usize::MAX));
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let start_time = Arc::new(AtomicU64::new(::std::u64::MAX));
+        let frames = Arc::new(AtomicU64::new(0));
         let uu = Uuid::new_v4();
-
-        self.control.push(thread::AudioThreadCommand::AddPlayer(thread::Player {
-            buf: c,
-            sample_rate: sample_rate,
-            start_time: start_time.clone(),
-            position: position.clone(),
+        self.control.push(thread::AudioThreadCommand::AddCapture(thread::Capture {
+            chans,
+            buf: p,
             active: active.clone(),
             alive: alive.clone(),
-            output_patch: output_patch.clone(),
-            volume: volume.clone(),
-            master_vol: master_vol.clone(),
-            kill_when_empty: kill_when_empty.clone(),
-            uuid: uu,
-            half_sent: false,
-            empty_sent: false
+            overflowed: overflowed.clone(),
+            start_time: start_time.clone(),
+            frames: frames.clone(),
+            uuid: uu
         }));
-
-        Sender {
+        Ok(Receiver {
+            buf: c,
+            chans: inputs.to_vec(),
+            overflowed,
+            start_time,
+            frames,
+            uuid: uu,
+            guard: CaptureGuard { active, alive }
+        })
+    }
+    /// Start recording a set of input channels to WAV file(s) at `path`.
+    ///
+    /// Recording stops when `Bouncer::finish()` is called.
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P, inputs: &[usize], spec: BounceSpec) -> EngineResult<Bouncer> {
+        // As with `start_bounce()`, check the channels before creating any files.
+        for &ch in inputs {
+            if ch >= self.inputs.len() || self.inputs[ch].is_none() {
+                Err(EngineError::NoSuchChannel)?
+            }
+        }
+        let writer = BounceWriter::new(path, inputs, self.backend.sample_rate(), spec)?;
+        let mut rx = self.new_receiver(writer.chans())?;
+        rx.set_active(true);
+        let Receiver { buf, overflowed, guard, .. } = rx;
+        Ok(Bouncer::spawn(writer, buf, overflowed, Some(guard)))
+    }
+    /// Start bouncing the output of a set of channels to WAV file(s) at `path`, alongside
+    /// playback.
+    ///
+    /// Only one bounce can be running at a time; starting another one will stop the first.
+    /// The audio thread will send back the old tap in a `TapRemoved` message.
+    pub fn start_bounce<P: AsRef<Path>>(&mut self, path: P, chans: &[usize], spec: BounceSpec) -> EngineResult<Bouncer> {
+        // Check the channels before creating any files, so a bad one doesn't leave any behind.
+        for &ch in chans {
+            if ch >= self.chans.len() || self.chans[ch].is_none() {
+                Err(EngineError::NoSuchChannel)?
+            }
+        }
+        let writer = BounceWriter::new(path, chans, self.backend.sample_rate(), spec)?;
+        let mut tap_chans = ArrayVec::new();
+        for &ch in writer.chans() {
+            tap_chans.push(ch);
+        }
+        let (p, c) = bounded_spsc_queue::make(STREAM_BUFFER_SIZE * tap_chans.len());
+        let overflowed = Arc::new(AtomicBool::new(false));
+        self.control.push(thread::AudioThreadCommand::SetTap(Some(thread::Tap {
+            chans: tap_chans,
             buf: p,
-            position: position,
-            active: active,
-            alive: alive,
-            output_patch: output_patch,
-            start_time: start_time,
-            sample_rate: sample_rate,
-            volume: volume.clone(),
-            master_vol: master_vol.clone(),
-            kill_when_empty: kill_when_empty.clone(),
-            original: true,
-            uuid: uu
+            overflowed: overflowed.clone()
+        })));
+        Ok(Bouncer::spawn(writer, c, overflowed, None))
+    }
+    /// Stop bouncing. Call `Bouncer::finish()` afterwards to finish writing the file(s).
+    pub fn stop_bounce(&mut self) {
+        self.control.push(thread::AudioThreadCommand::SetTap(None));
+    }
+    /// Make a new sender, playing samples at `sample_rate`.
+    ///
+    /// If the sample rate differs from the backend's, the samples will be resampled with
+    /// the default `ResampleQuality`.
+    pub fn new_sender(&mut self, sample_rate: u64) -> BufferSender {
+        self.new_sender_ext(sample_rate, 1, None, ResampleQuality::default())
+    }
+    /// Make a new sender, playing samples at `sample_rate`, and resampling them with the given
+    /// quality if needed.
+    pub fn new_sender_with_quality(&mut self, sample_rate: u64, quality: ResampleQuality) -> BufferSender {
+        self.new_sender_ext(sample_rate, 1, None, quality)
+    }
+    /// Make a new multichannel sender, playing frames of `channels` interleaved samples at
+    /// `sample_rate`, and resampling them with the given quality if needed.
+    ///
+    /// All of the stream's channels share one buffer, position, start time and volume, so they
+    /// can never drift apart. Each channel has its own output patch (see
+    /// `Sender::set_output_patch_for()`).
+    ///
+    /// Returns `EngineError::LimitExceeded` if `channels` is 0 or greater than
+    /// `MAX_SENDER_CHANNELS`.
+    pub fn new_multichannel_sender(&mut self, sample_rate: u64, channels: usize, quality: ResampleQuality) -> EngineResult<BufferSender> {
+        if channels == 0 || channels > MAX_SENDER_CHANNELS {
+            Err(EngineError::LimitExceeded)?
         }
+        Ok(self.new_sender_ext(sample_rate, channels, None, quality))
+    }
+    pub fn new_sender_with_master<T>(&mut self, master: &Sender<T>) -> BufferSender {
+        let master_vol = master.master_vol.clone();
+        self.new_sender_ext(master.sample_rate, 1, Some(master_vol), ResampleQuality::default())
+    }
+    fn new_sender_ext(&mut self, sample_rate: u64, channels: usize, master_vol: Option<Arc<SharedValue<Parameter<f32>>>>, quality: ResampleQuality) -> BufferSender {
+        let output_rate = self.backend.sample_rate();
+        let (send, player) = make_sender(sample_rate, channels, master_vol, quality, output_rate, self.clock.clone());
+        self.control.push(thread::AudioThreadCommand::AddPlayer(player));
+        send
     }
 }
+/// Make a new `BufferSender`, and the `Player` it controls.
+fn make_sender(sample_rate: u64, channels: usize, master_vol: Option<Arc<SharedValue<Parameter<f32>>>>, quality: ResampleQuality, output_rate: u64, clock: Arc<dyn Clock>) -> (BufferSender, thread::Player) {
+    let (p, c) = bounded_spsc_queue::make(STREAM_BUFFER_SIZE * channels);
+    let active = Arc::new(AtomicBool::new(false));
+    let alive = Arc::new(AtomicBool::new(false));
+    let kill_when_empty = Arc::new(AtomicBool::new(false));
+    let paused = Arc::new(AtomicBool::new(false));
+    let group = Arc::new(AtomicU64::new(0));
+    let stop_time = Arc::new(AtomicU64::new(::std::u64::MAX));
+    let stop_position = Arc::new(AtomicU64::new(::std::u64::MAX));
+    let kill_on_stop = Arc::new(AtomicBool::new(false));
+    let stop_declick = Arc::new(AtomicBool::new(false));
+    let position = Arc::new(AtomicU64::new(0));
+    let start_time = Arc::new(AtomicU64::new(0));
+    let volume = Arc::new(SharedValue::new(Parameter::Raw(1.0)));
+    let master_vol = master_vol.unwrap_or_else(|| Arc::new(SharedValue::new(Parameter::Raw(1.0))));
+    let routing = Arc::new(SharedValue::new(Routing::new()));
+    let meters = Arc::new(PlayerMeters::new(channels));
+    let seek = Arc::new(AtomicPtr::new(::std::ptr::null_mut()));
+    let loop_pending = Arc::new(AtomicPtr::new(::std::ptr::null_mut()));
+    let devamp = Arc::new(AtomicBool::new(false));
+    let uu = Uuid::new_v4();
+
+    let player = thread::Player {
+        buf: c,
+        sample_rate,
+        channels,
+        start_time: start_time.clone(),
+        position: position.clone(),
+        active: active.clone(),
+        alive: alive.clone(),
+        routing: routing.clone(),
+        volume: volume.clone(),
+        master_vol: master_vol.clone(),
+        kill_when_empty: kill_when_empty.clone(),
+        paused: paused.clone(),
+        was_paused: false,
+        group: group.clone(),
+        stop_time: stop_time.clone(),
+        stop_position: stop_position.clone(),
+        kill_on_stop: kill_on_stop.clone(),
+        stop_declick: stop_declick.clone(),
+        uuid: uu,
+        resampler: Resampler::new(quality, sample_rate, output_rate, channels),
+        seek: seek.clone(),
+        seek_tail: None,
+        read_pos: 0,
+        loop_pending: loop_pending.clone(),
+        lp: None,
+        devamp: devamp.clone(),
+        meters: meters.clone(),
+        meter_peak: [0.0; MAX_SENDER_CHANNELS],
+        meter_sum_squares: [0.0; MAX_SENDER_CHANNELS],
+        half_sent: false,
+        empty_sent: false
+    };
+
+    (Sender {
+        buf: p,
+        position,
+        active,
+        alive,
+        routing,
+        start_time,
+        sample_rate,
+        channels,
+        seek,
+        loop_pending,
+        devamp,
+        quality,
+        output_rate,
+        volume: volume.clone(),
+        master_vol: master_vol.clone(),
+        kill_when_empty: kill_when_empty.clone(),
+        paused,
+        group,
+        stop_time,
+        stop_position,
+        kill_on_stop,
+        stop_declick,
+        original: true,
+        clock,
+        meters,
+        uuid: uu
+    }, player)
+}
@@ -0,0 +1,161 @@
+//! Level metering, for drawing meters.
+//!
+//! The audio thread measures the peak and RMS level of every channel (after its output stage),
+//! and of every player that has metering turned on, once per period. Readings can be taken at any
+//! time from the main thread, with `EngineContext::channel_meter()` and `Sender::meter()`.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64};
+use std::sync::atomic::Ordering::*;
+use std::time::Duration;
+use super::ONE_SECOND_IN_NANOSECONDS;
+
+/// A reading from a meter. All levels are linear (1.0 is full scale).
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct MeterReading {
+    /// The peak level, decaying according to the meter ballistics.
+    pub peak: f32,
+    /// The highest recent peak, held according to the meter ballistics.
+    pub peak_hold: f32,
+    /// The RMS level, averaged over the meter ballistics' RMS window.
+    pub rms: f32
+}
+impl MeterReading {
+    /// Convert a linear level to decibels relative to full scale.
+    pub fn to_dbfs(level: f32) -> f32 {
+        20.0 * level.log10()
+    }
+}
+
+/// How meters respond to changes in level.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MeterBallistics {
+    /// How long the highest peak is held for.
+    pub hold: Duration,
+    /// How fast the peak level falls, in decibels per second.
+    pub decay: f32,
+    /// The time constant of the RMS level's averaging.
+    pub rms_window: Duration
+}
+impl Default for MeterBallistics {
+    fn default() -> Self {
+        MeterBallistics {
+            hold: Duration::from_secs(1),
+            decay: 20.0,
+            rms_window: Duration::from_millis(300)
+        }
+    }
+}
+
+fn duration_nanos(dur: Duration) -> u64 {
+    dur.as_secs() * ONE_SECOND_IN_NANOSECONDS + dur.subsec_nanos() as u64
+}
+
+/// `MeterBallistics`, in a form that can be shared with the audio thread.
+pub(crate) struct AtomicBallistics {
+    hold: AtomicU64,
+    decay: AtomicU32,
+    rms_window: AtomicU64
+}
+impl AtomicBallistics {
+    pub(crate) fn new(b: MeterBallistics) -> Self {
+        let ret = AtomicBallistics {
+            hold: AtomicU64::new(0),
+            decay: AtomicU32::new(0),
+            rms_window: AtomicU64::new(0)
+        };
+        ret.set(b);
+        ret
+    }
+    pub(crate) fn set(&self, b: MeterBallistics) {
+        self.hold.store(duration_nanos(b.hold), Relaxed);
+        self.decay.store(b.decay.to_bits(), Relaxed);
+        self.rms_window.store(duration_nanos(b.rms_window), Relaxed);
+    }
+    pub(crate) fn get(&self) -> MeterBallistics {
+        let rms_window = self.rms_window.load(Relaxed);
+        let hold = self.hold.load(Relaxed);
+        MeterBallistics {
+            hold: Duration::new(hold / ONE_SECOND_IN_NANOSECONDS, (hold % ONE_SECOND_IN_NANOSECONDS) as u32),
+            decay: f32::from_bits(self.decay.load(Relaxed)),
+            rms_window: Duration::new(rms_window / ONE_SECOND_IN_NANOSECONDS, (rms_window % ONE_SECOND_IN_NANOSECONDS) as u32)
+        }
+    }
+}
+
+/// The levels of one signal, written by the audio thread.
+#[derive(Debug, Default)]
+pub struct Meter {
+    peak: AtomicU32,
+    hold: AtomicU32,
+    hold_time: AtomicU64,
+    /// The averaged mean square (not root mean square) level.
+    mean_square: AtomicU32
+}
+impl Meter {
+    /// Take a reading from this meter.
+    pub fn reading(&self) -> MeterReading {
+        MeterReading {
+            peak: f32::from_bits(self.peak.load(Relaxed)),
+            peak_hold: f32::from_bits(self.hold.load(Relaxed)),
+            rms: f32::from_bits(self.mean_square.load(Relaxed)).sqrt()
+        }
+    }
+    /// Set all levels back to zero.
+    #[inline(always)]
+    pub(crate) fn reset(&self) {
+        self.peak.store(0, Relaxed);
+        self.hold.store(0, Relaxed);
+        self.hold_time.store(0, Relaxed);
+        self.mean_square.store(0, Relaxed);
+    }
+    /// Update the meter with one period's measurements: its peak level, and the sum of the
+    /// squares of its samples.
+    #[inline(always)]
+    pub(crate) fn update(&self, peak: f32, sum_squares: f32, nframes: usize, time: u64, period_ns: u64, b: &AtomicBallistics) {
+        let period_secs = period_ns as f32 / ONE_SECOND_IN_NANOSECONDS as f32;
+        let decay = f32::from_bits(b.decay.load(Relaxed));
+        let old_peak = f32::from_bits(self.peak.load(Relaxed));
+        let new_peak = peak.max(old_peak * 10f32.powf(-decay * period_secs / 20.0));
+        self.peak.store(new_peak.to_bits(), Relaxed);
+        let hold = f32::from_bits(self.hold.load(Relaxed));
+        let hold_time = self.hold_time.load(Relaxed);
+        if new_peak >= hold || time >= hold_time + b.hold.load(Relaxed) {
+            self.hold.store(new_peak.to_bits(), Relaxed);
+            self.hold_time.store(time, Relaxed);
+        }
+        let window = b.rms_window.load(Relaxed).max(1) as f32;
+        let coeff = 1.0 - (-(period_ns as f32) / window).exp();
+        let ms = f32::from_bits(self.mean_square.load(Relaxed));
+        let period_ms = if nframes > 0 { sum_squares / nframes as f32 } else { 0.0 };
+        self.mean_square.store((ms + (period_ms - ms) * coeff).to_bits(), Relaxed);
+    }
+}
+
+/// The meters for a player: one per channel of its stream, plus whether they're in use.
+#[derive(Debug, Default)]
+pub struct PlayerMeters {
+    pub(crate) enabled: AtomicBool,
+    pub(crate) meters: Vec<Meter>
+}
+impl PlayerMeters {
+    pub(crate) fn new(channels: usize) -> Self {
+        PlayerMeters {
+            enabled: AtomicBool::new(false),
+            meters: (0..channels).map(|_| Meter::default()).collect()
+        }
+    }
+}
+
+/// The meters for every channel, along with the ballistics used for all meters.
+pub(crate) struct MeterBank {
+    pub(crate) ballistics: AtomicBallistics,
+    pub(crate) chans: Vec<Meter>
+}
+impl MeterBank {
+    pub(crate) fn new(nchans: usize) -> Self {
+        MeterBank {
+            ballistics: AtomicBallistics::new(MeterBallistics::default()),
+            chans: (0..nchans).map(|_| Meter::default()).collect()
+        }
+    }
+}
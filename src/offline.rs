@@ -0,0 +1,229 @@
+//! Rendering audio without a JACK server.
+//!
+//! The `OfflineBackend` drives the same audio thread code that JACK would, but from a loop
+//! that you own, with a virtual clock that only advances when you ask it to render. This
+//! makes it possible to test cues, fades and patching deterministically, and to render
+//! faster than realtime.
+//!
+//! Input channels capture whatever you give them with `set_input()` before each render, and
+//! silence otherwise.
+
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use super::{EngineContext, EngineResult, ONE_SECOND_IN_NANOSECONDS};
+use clock::{Clock, VirtualClock};
+use backend::{Backend, CallbackContext, DeviceContext};
+use errors::EngineError;
+use bounce::BounceWriter;
+
+/// A port on the offline renderer. Refers to one of the renderer's buffers.
+#[derive(Copy, Clone, Debug)]
+pub struct OfflinePort {
+    idx: usize
+}
+
+/// Hands out the offline renderer's buffers to the audio thread code.
+struct OfflineCallbackContext<'a> {
+    nframes: u32,
+    bufs: &'a [UnsafeCell<Vec<f32>>]
+}
+impl<'a> CallbackContext for OfflineCallbackContext<'a> {
+    type Port = OfflinePort;
+    fn nframes(&self) -> u32 {
+        self.nframes
+    }
+    fn get_port_buffer(&self, port: &OfflinePort) -> Option<*mut [f32]> {
+        self.bufs.get(port.idx).map(|buf| unsafe { (&mut *buf.get()).as_mut_slice() as *mut [f32] })
+    }
+}
+
+/// Backend that renders audio offline, driven by calls to `render()`.
+///
+/// Time only passes when you call `render()`, which advances the backend's `VirtualClock` (its
+/// default clock) by one period. The engine MUST use that clock: starting it with any other
+/// (with `EngineContext::with_backend_and_clock()`) fails with `EngineError::InvalidBackendState`.
+pub struct OfflineBackend {
+    bufs: Vec<UnsafeCell<Vec<f32>>>,
+    /// Whether each of `bufs` belongs to an input port.
+    is_input: Vec<bool>,
+    free: Vec<usize>,
+    dctx: Option<DeviceContext<OfflinePort>>,
+    clock: Arc<VirtualClock>,
+    sample_rate: u64,
+    buffer_size: u32,
+    frames: u64,
+    /// Leftover fractions of a nanosecond from advancing the clock, times the sample rate.
+    clock_rem: u64
+}
+/// An `EngineContext` that renders audio offline.
+pub type OfflineContext = EngineContext<OfflineBackend>;
+
+impl OfflineBackend {
+    /// Make a new offline renderer, running at the given sample rate, and rendering
+    /// `buffer_size` frames per call to `render()`.
+    pub fn new(sample_rate: u64, buffer_size: u32) -> Self {
+        OfflineBackend {
+            bufs: Vec::new(),
+            is_input: Vec::new(),
+            free: Vec::new(),
+            dctx: None,
+            clock: Arc::new(VirtualClock::new(0)),
+            sample_rate,
+            buffer_size,
+            frames: 0,
+            clock_rem: 0
+        }
+    }
+    /// Get the number of frames rendered so far.
+    pub fn frames_rendered(&self) -> u64 {
+        self.frames
+    }
+    /// Get the virtual clock this backend advances.
+    pub fn clock(&self) -> &Arc<VirtualClock> {
+        &self.clock
+    }
+    /// Render one period (`buffer_size()` frames) of audio, advancing the virtual clock
+    /// accordingly.
+    ///
+    /// Afterwards, the rendered audio can be obtained with `buffer()`. Does nothing if the
+    /// backend hasn't been started yet.
+    pub fn render(&mut self) {
+        let time = self.clock.now();
+        let nframes = self.buffer_size;
+        let dctx = match self.dctx {
+            Some(ref mut d) => d,
+            None => return
+        };
+        for (buf, &input) in self.bufs.iter_mut().zip(self.is_input.iter()) {
+            let buf = buf.get_mut();
+            if !input {
+                buf.clear();
+            }
+            buf.resize(nframes as usize, 0.0);
+        }
+        let ctx = OfflineCallbackContext {
+            nframes,
+            bufs: &self.bufs
+        };
+        dctx.process_at(&ctx, time);
+        for (buf, &input) in self.bufs.iter_mut().zip(self.is_input.iter()) {
+            if input {
+                buf.get_mut().clear();
+            }
+        }
+        self.frames += nframes as u64;
+        let nanos = nframes as u64 * ONE_SECOND_IN_NANOSECONDS + self.clock_rem;
+        self.clock_rem = nanos % self.sample_rate;
+        self.clock.advance(nanos / self.sample_rate);
+    }
+    /// Get the audio rendered to a given port by the last call to `render()`.
+    pub fn buffer(&self, port: &OfflinePort) -> &[f32] {
+        unsafe { (&*self.bufs[port.idx].get()).as_slice() }
+    }
+    /// Set the audio an input port will capture during the next call to `render()`.
+    ///
+    /// Anything past `buffer_size()` frames is ignored, and if fewer frames are given, the rest
+    /// of the period is silent.
+    pub fn set_input(&mut self, port: &OfflinePort, data: &[f32]) {
+        let len = ::std::cmp::min(data.len(), self.buffer_size as usize);
+        let buf = self.bufs[port.idx].get_mut();
+        buf.clear();
+        buf.extend_from_slice(&data[..len]);
+    }
+    fn alloc_port(&mut self, input: bool) -> OfflinePort {
+        let idx = match self.free.pop() {
+            Some(idx) => idx,
+            None => {
+                self.bufs.push(UnsafeCell::new(Vec::new()));
+                self.is_input.push(false);
+                self.bufs.len() - 1
+            }
+        };
+        self.is_input[idx] = input;
+        self.bufs[idx].get_mut().clear();
+        OfflinePort { idx }
+    }
+}
+impl Backend for OfflineBackend {
+    type Port = OfflinePort;
+    fn register_port(&mut self, _: &str) -> EngineResult<OfflinePort> {
+        Ok(self.alloc_port(false))
+    }
+    fn register_input_port(&mut self, _: &str) -> EngineResult<OfflinePort> {
+        Ok(self.alloc_port(true))
+    }
+    fn unregister_port(&mut self, port: OfflinePort) -> EngineResult<()> {
+        self.free.push(port.idx);
+        Ok(())
+    }
+    /// Connections are meaningless offline, so this does nothing.
+    fn connect_port(&mut self, _: &OfflinePort, _: &str) -> EngineResult<()> {
+        Ok(())
+    }
+    /// Connections are meaningless offline, so this does nothing.
+    fn disconnect_port(&mut self, _: &OfflinePort, _: &str) -> EngineResult<()> {
+        Ok(())
+    }
+    fn sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+    fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+    fn default_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+    fn start(&mut self, handler: DeviceContext<OfflinePort>) -> EngineResult<()> {
+        if self.dctx.is_some() {
+            Err(EngineError::InvalidBackendState)?
+        }
+        // `render()` only advances our own clock, so no other clock would ever move.
+        let ours = &*self.clock as *const VirtualClock as *const ();
+        let theirs = &*handler.clock as *const dyn Clock as *const ();
+        if ours != theirs {
+            Err(EngineError::InvalidBackendState)?
+        }
+        self.dctx = Some(handler);
+        Ok(())
+    }
+}
+impl EngineContext<OfflineBackend> {
+    /// Initialise the SQA Engine on an offline renderer (see `OfflineBackend::new()`).
+    pub fn offline(sample_rate: u64, buffer_size: u32) -> Self {
+        Self::with_backend(OfflineBackend::new(sample_rate, buffer_size))
+            .expect("starting an OfflineBackend can't fail")
+    }
+    /// Render one period of audio. See `OfflineBackend::render()`.
+    pub fn render(&mut self) {
+        self.backend.render();
+    }
+    /// Get the audio rendered to a given channel by the last call to `render()`.
+    ///
+    /// Returns `None` if the channel doesn't exist.
+    pub fn buffer(&self, ch: usize) -> Option<&[f32]> {
+        match self.chans.get(ch) {
+            Some(Some(port)) => Some(self.backend.buffer(port)),
+            _ => None
+        }
+    }
+    /// Set the audio an input channel will capture during the next call to `render()`. See
+    /// `OfflineBackend::set_input()`.
+    pub fn set_input(&mut self, ch: usize, data: &[f32]) -> EngineResult<()> {
+        match self.inputs.get(ch) {
+            Some(Some(port)) => {
+                self.backend.set_input(port, data);
+                Ok(())
+            },
+            _ => Err(EngineError::NoSuchChannel)
+        }
+    }
+    /// Render one period of audio, and write the channels being bounced by `writer` to it.
+    pub fn render_to(&mut self, writer: &mut BounceWriter) -> EngineResult<()> {
+        self.render();
+        let mut bufs = vec![];
+        for &ch in writer.chans() {
+            bufs.push(self.buffer(ch).ok_or(EngineError::NoSuchChannel)?);
+        }
+        writer.write_buffers(&bufs)
+    }
+}
@@ -6,26 +6,141 @@ use std::sync::atomic::Ordering::*;
 use std::sync::Arc;
 use std::fmt::Display;
 use std::time::Duration;
+use std::f32::consts::PI;
+
+/// A value that can be faded along any `FadeCurve`.
+pub trait Fadeable: Mul<f32, Output=Self> + Sub<Self, Output=Self> + Add<Self, Output=Self> + Copy {
+    /// The magnitude of this value, as a linear gain, for curves that depend on it (like
+    /// `FadeCurve::LinearDb`).
+    fn level(&self) -> f32;
+}
+impl Fadeable for f32 {
+    fn level(&self) -> f32 {
+        self.abs()
+    }
+}
+
+/// The shape of a fade.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FadeCurve {
+    /// A straight line from start to end.
+    Linear,
+    /// Starts slowly, and speeds up towards the end.
+    Exponential,
+    /// Starts quickly, and slows down towards the end.
+    Logarithmic,
+    /// Starts and ends slowly, changing fastest in the middle.
+    SCurve,
+    /// Follows a quarter sine wave (or cosine wave, when fading down), so that two equal-power
+    /// fades of opposite direction crossfade without a dip in loudness.
+    EqualPower,
+    /// A straight line in decibels, so that loudness changes at a constant rate. Silence is
+    /// treated as -60dB.
+    LinearDb
+}
+impl Default for FadeCurve {
+    fn default() -> Self {
+        FadeCurve::Linear
+    }
+}
+/// How steep the exponential and logarithmic curves are.
+const CURVE_STEEPNESS: f32 = 4.0;
+/// The level below which `FadeCurve::LinearDb` treats values as silence (-60dB).
+const DB_FLOOR: f32 = 0.001;
+impl FadeCurve {
+    /// Get how far through the fade's change in value a fade with this curve is, given how far
+    /// through its duration it is (both from 0.0 to 1.0), and the levels (see `Fadeable::level()`)
+    /// it fades from and to.
+    pub fn shape(&self, from: f32, to: f32, pct: f32) -> f32 {
+        use self::FadeCurve::*;
+        match *self {
+            Linear => pct,
+            Exponential => ((CURVE_STEEPNESS * pct).exp() - 1.0) / (CURVE_STEEPNESS.exp() - 1.0),
+            Logarithmic => (1.0 + (CURVE_STEEPNESS.exp() - 1.0) * pct).ln() / CURVE_STEEPNESS,
+            SCurve => 0.5 - 0.5 * (PI * pct).cos(),
+            EqualPower => {
+                if to >= from {
+                    (pct * PI / 2.0).sin()
+                }
+                else {
+                    1.0 - (pct * PI / 2.0).cos()
+                }
+            },
+            LinearDb => {
+                let from = from.max(DB_FLOOR);
+                let to = to.max(DB_FLOOR);
+                if from == to {
+                    return pct;
+                }
+                let db_from = 20.0 * from.log10();
+                let db_to = 20.0 * to.log10();
+                let level = 10f32.powf((db_from + (db_to - db_from) * pct) / 20.0);
+                (level - from) / (to - from)
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct FadeDetails<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy {
     from: T,
     delta: T,
+    curve: FadeCurve,
+    /// The levels of `from` and `to()`, for curves that need them; only set with the curve.
+    levels: (f32, f32),
     start_time: Arc<AtomicU64>,
     duration: Arc<AtomicU64>,
     active: Arc<AtomicBool>,
+    /// Whether the audio thread has said that this fade is complete.
+    notified: Arc<AtomicBool>,
+    id_ptr: Arc<()>
+}
+/// One point of an `Envelope`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Breakpoint<T> {
+    /// The time of this point, in nanoseconds after the envelope starts.
+    pub time: u64,
+    /// The value the envelope reaches at this point.
+    pub value: T,
+    /// The shape of the segment leading up to this point, from the previous one.
+    pub curve: FadeCurve,
+    /// The level of `value`, for curves that need it.
+    level: f32
+}
+/// A series of timed breakpoints, with a curved segment between each of them.
+///
+/// Before it's started, an envelope stays at its initial value; once it's been through all of
+/// its breakpoints, it stays at the value of the last one.
+#[derive(Clone, Debug)]
+pub struct Envelope<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy {
+    initial: T,
+    /// The level of `initial`, for curves that need it.
+    initial_level: f32,
+    points: Vec<Breakpoint<T>>,
+    start_time: Arc<AtomicU64>,
+    active: Arc<AtomicBool>,
+    /// Whether the audio thread has said that this envelope is complete.
+    notified: Arc<AtomicBool>,
     id_ptr: Arc<()>
 }
 #[derive(Clone, Debug)]
 pub enum Parameter<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy + Display {
     Raw(T),
     TimedRaw(T, u64, T),
-    LinearFade(FadeDetails<T>)
+    /// A fade that is always linear, whatever the `FadeDetails`' curve is.
+    LinearFade(FadeDetails<T>),
+    /// A fade following the `FadeDetails`' curve.
+    Fade(FadeDetails<T>),
+    Envelope(Envelope<T>)
 }
 impl<T> Parameter<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy + Display {
     pub fn handle_linear(fd: &FadeDetails<T>, time: u64) -> T {
         fd.from() + (fd.delta() * fd.percentage_complete(time))
     }
+    pub fn handle_curve(fd: &FadeDetails<T>, time: u64) -> T {
+        let pct = fd.percentage_complete(time);
+        fd.from() + (fd.delta() * fd.curve().shape(fd.levels.0, fd.levels.1, pct))
+    }
     pub fn get(&self, time: u64) -> T {
         use self::Parameter::*;
         match *self {
@@ -34,9 +149,129 @@ impl<T> Parameter<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Out
                 if time >= thresh { now }
                 else { before }
             },
-            LinearFade(ref fd) => Self::handle_linear(fd, time)
+            LinearFade(ref fd) => Self::handle_linear(fd, time),
+            Fade(ref fd) => Self::handle_curve(fd, time),
+            Envelope(ref env) => Self::handle_envelope(env, time)
+        }
+    }
+    /// If this parameter is a fade or envelope that has completed as of `time`, and this
+    /// hasn't returned its ID already, return its ID.
+    ///
+    /// This is how the audio thread decides when to send `FadeComplete` messages. Restarting
+    /// the fade (with `set_start_time()`) lets it be returned again.
+    pub fn take_completion(&self, time: u64) -> Option<&Arc<()>> {
+        use self::Parameter::*;
+        let (complete, notified, id_ptr) = match *self {
+            LinearFade(ref fd) | Fade(ref fd) => (fd.is_complete(time), &fd.notified, &fd.id_ptr),
+            Envelope(ref env) => (env.is_complete(time), &env.notified, &env.id_ptr),
+            _ => return None
+        };
+        if complete && !notified.swap(true, Relaxed) {
+            Some(id_ptr)
+        }
+        else {
+            None
         }
     }
+    pub fn handle_envelope(env: &Envelope<T>, time: u64) -> T {
+        let start_time = env.start_time();
+        if !env.active() || time <= start_time {
+            return env.initial;
+        }
+        let elapsed = time - start_time;
+        let mut prev_time = 0;
+        let mut prev_value = env.initial;
+        let mut prev_level = env.initial_level;
+        for pt in env.points.iter() {
+            if elapsed < pt.time {
+                let pct = (elapsed - prev_time) as f32 / (pt.time - prev_time) as f32;
+                return prev_value + (pt.value - prev_value) * pt.curve.shape(prev_level, pt.level, pct);
+            }
+            prev_time = pt.time;
+            prev_value = pt.value;
+            prev_level = pt.level;
+        }
+        prev_value
+    }
+}
+impl<T> Envelope<T> where T: Fadeable {
+    /// Make an envelope that starts at `initial`, with no breakpoints.
+    ///
+    /// Some curves depend on the levels being faded between, so envelopes need `Fadeable` values.
+    pub fn new(initial: T) -> Self {
+        Self::new_with_id(initial, Arc::new(()))
+    }
+    pub fn new_with_id(initial: T, idp: Arc<()>) -> Self {
+        Self {
+            initial,
+            initial_level: initial.level(),
+            points: Vec::new(),
+            start_time: Arc::new(AtomicU64::new(0)),
+            active: Arc::new(AtomicBool::new(false)),
+            notified: Arc::new(AtomicBool::new(false)),
+            id_ptr: idp
+        }
+    }
+    /// Add a breakpoint, a given time after the envelope starts, reached via a segment
+    /// with the given curve.
+    ///
+    /// Breakpoints can be added in any order. Adding one at the same time as an existing one
+    /// replaces it.
+    pub fn add_point(&mut self, time: Duration, value: T, curve: FadeCurve) {
+        let nanos = time.as_secs() * super::ONE_SECOND_IN_NANOSECONDS + time.subsec_nanos() as u64;
+        self.add_point_nanos(nanos, value, curve);
+    }
+    pub fn add_point_nanos(&mut self, time: u64, value: T, curve: FadeCurve) {
+        let pt = Breakpoint { time, value, curve, level: value.level() };
+        match self.points.iter().position(|p| p.time >= time) {
+            Some(idx) if self.points[idx].time == time => self.points[idx] = pt,
+            Some(idx) => self.points.insert(idx, pt),
+            None => self.points.push(pt)
+        }
+    }
+}
+impl<T> Envelope<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy {
+    pub fn points(&self) -> &[Breakpoint<T>] {
+        &self.points
+    }
+    pub fn initial(&self) -> T {
+        self.initial
+    }
+    /// Get the value of the last breakpoint (or the initial value, if there aren't any).
+    pub fn last(&self) -> T {
+        self.points.last().map(|p| p.value).unwrap_or(self.initial)
+    }
+    /// Get the time from the start of the envelope to its last breakpoint.
+    pub fn duration_nanos(&self) -> u64 {
+        self.points.last().map(|p| p.time).unwrap_or(0)
+    }
+    pub fn set_start_time(&mut self, st: u64) {
+        self.start_time.store(st, Relaxed);
+        self.notified.store(false, Relaxed);
+    }
+    pub fn start_time(&self) -> u64 {
+        self.start_time.load(Relaxed)
+    }
+    pub fn start_from_time(&mut self, ti: u64) {
+        self.set_start_time(ti);
+        self.set_active(true);
+    }
+    pub fn set_active(&mut self, active: bool) {
+        self.active.store(active, Relaxed);
+    }
+    pub fn active(&self) -> bool {
+        self.active.load(Relaxed)
+    }
+    /// Whether the envelope has been through all of its breakpoints, as of `time`.
+    pub fn is_complete(&self, time: u64) -> bool {
+        self.active() && time >= self.start_time() + self.duration_nanos()
+    }
+    pub fn id_ptr(&self) -> &Arc<()> {
+        &self.id_ptr
+    }
+    pub fn same_id_as(&self, env: &Envelope<T>) -> bool {
+        Arc::ptr_eq(&self.id_ptr, &env.id_ptr)
+    }
 }
 impl<T> FadeDetails<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, Output=T> + Copy {
     fn _new(from: T, to: T, id_ptr: Arc<()>) -> Self {
@@ -44,7 +279,10 @@ impl<T> FadeDetails<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, O
         let start_time = Arc::new(AtomicU64::new(0));
         let duration = Arc::new(AtomicU64::new(0));
         let active = Arc::new(AtomicBool::new(false));
-        Self { from, delta, start_time, duration, active, id_ptr }
+        let curve = FadeCurve::default();
+        let levels = (0.0, 0.0);
+        let notified = Arc::new(AtomicBool::new(false));
+        Self { from, delta, curve, levels, start_time, duration, active, notified, id_ptr }
     }
     pub fn new(from: T, to: T) -> Self {
         Self::_new(from, to, Arc::new(()))
@@ -55,8 +293,15 @@ impl<T> FadeDetails<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, O
     pub fn from(&self) -> T {
         self.from
     }
+    pub fn to(&self) -> T {
+        self.from + self.delta
+    }
+    pub fn curve(&self) -> FadeCurve {
+        self.curve
+    }
     pub fn set_start_time(&mut self, st: u64) {
         self.start_time.store(st, Relaxed);
+        self.notified.store(false, Relaxed);
     }
     pub fn start_time(&self) -> u64 {
         self.start_time.load(Relaxed)
@@ -110,7 +355,20 @@ impl<T> FadeDetails<T> where T: Mul<f32, Output=T> + Sub<T, Output=T> + Add<T, O
             ns_delta / dur
         }
     }
+    /// Whether the fade has finished, as of `time`.
+    pub fn is_complete(&self, time: u64) -> bool {
+        self.active.load(Relaxed) && time >= self.start_time() + self.duration_nanos()
+    }
     pub fn same_id_as(&self, fd: &FadeDetails<T>) -> bool {
         Arc::ptr_eq(&self.id_ptr, &fd.id_ptr)
     }
 }
+impl<T> FadeDetails<T> where T: Fadeable {
+    /// Set the shape of the fade. Only `Parameter::Fade` takes any notice of it.
+    ///
+    /// Some curves depend on the levels being faded between, so this needs a `Fadeable` value.
+    pub fn set_curve(&mut self, curve: FadeCurve) {
+        self.levels = (self.from.level(), self.to().level());
+        self.curve = curve;
+    }
+}
@@ -0,0 +1,193 @@
+//! Realtime-safe sample rate conversion, for players whose sample rate differs from the engine's.
+
+use bounded_spsc_queue::Consumer;
+use std::f64::consts::PI;
+
+/// The maximum number of taps any interpolator uses.
+const MAX_TAPS: usize = 16;
+/// The number of fractional positions the windowed-sinc kernel is precomputed for.
+const SINC_PHASES: usize = 256;
+
+/// Somewhere interleaved samples can be pulled from, one at a time.
+pub trait SampleSource {
+    /// Get the next sample, or silence if there isn't one.
+    fn next_sample(&mut self) -> f32;
+}
+impl SampleSource for Consumer<f32> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> f32 {
+        self.try_pop().unwrap_or(0.0)
+    }
+}
+
+/// How good (and how expensive) sample rate conversion should be.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Linear interpolation between adjacent samples. Cheap, but dull and aliased.
+    Linear,
+    /// Cubic (Catmull-Rom) interpolation over 4 samples.
+    Cubic,
+    /// 16-tap Blackman-windowed sinc interpolation, band-limited when downsampling.
+    Sinc
+}
+impl ResampleQuality {
+    fn taps(&self) -> usize {
+        use self::ResampleQuality::*;
+        match *self {
+            Linear => 2,
+            Cubic => 4,
+            Sinc => MAX_TAPS
+        }
+    }
+}
+impl Default for ResampleQuality {
+    fn default() -> Self {
+        ResampleQuality::Cubic
+    }
+}
+
+/// Converts a stream of (interleaved) frames at one rate to another, pulling frames from a buffer
+/// as needed.
+///
+/// Allocation only happens when the `Resampler` is created, on the main thread.
+pub struct Resampler {
+    quality: ResampleQuality,
+    /// Source frames per output frame.
+    ratio: f64,
+    /// The number of channels in each frame.
+    channels: usize,
+    /// The last `taps` source samples of each channel, `MAX_TAPS` apart. The sample at the
+    /// current position is at `taps / 2 - 1`.
+    hist: Box<[f32]>,
+    /// How far between the current source sample and the next one we are.
+    frac: f64,
+    /// Whether `hist` has been filled since the last reset.
+    primed: bool,
+    /// Precomputed windowed-sinc kernel, `SINC_PHASES + 1` rows of `MAX_TAPS` taps.
+    kernel: Option<Box<[f32]>>
+}
+impl Resampler {
+    /// Make a resampler converting `channels` channels from `from` Hz to `to` Hz.
+    pub fn new(quality: ResampleQuality, from: u64, to: u64, channels: usize) -> Self {
+        let ratio = from as f64 / to as f64;
+        let kernel = if quality == ResampleQuality::Sinc {
+            Some(Self::make_kernel(ratio))
+        } else {
+            None
+        };
+        Resampler {
+            quality, ratio, channels, kernel,
+            hist: vec![0.0; MAX_TAPS * channels].into_boxed_slice(),
+            frac: 0.0,
+            primed: false
+        }
+    }
+    fn make_kernel(ratio: f64) -> Box<[f32]> {
+        // Lower the cutoff when downsampling, to avoid aliasing.
+        let cutoff = if ratio > 1.0 { 1.0 / ratio } else { 1.0 };
+        let half = (MAX_TAPS / 2) as f64;
+        let centre = (MAX_TAPS / 2 - 1) as f64;
+        let mut ret = Vec::with_capacity((SINC_PHASES + 1) * MAX_TAPS);
+        for phase in 0..(SINC_PHASES + 1) {
+            let frac = phase as f64 / SINC_PHASES as f64;
+            for k in 0..MAX_TAPS {
+                let x = k as f64 - centre - frac;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * cutoff * x).sin() / (PI * cutoff * x)
+                };
+                let window = if x.abs() >= half {
+                    0.0
+                } else {
+                    0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos()
+                };
+                ret.push((cutoff * sinc * window) as f32);
+            }
+        }
+        ret.into_boxed_slice()
+    }
+    /// Forget all history, so that the next frame produced is the next one in the buffer.
+    ///
+    /// This MUST be called whenever frames are skipped.
+    #[inline(always)]
+    pub fn reset(&mut self) {
+        self.primed = false;
+    }
+    /// The number of source frames that need to be in the buffer to produce `nframes` frames.
+    #[inline(always)]
+    pub fn source_needed(&self, nframes: usize) -> usize {
+        let mut ret = (nframes as f64 * self.ratio + self.frac).ceil() as usize;
+        if !self.primed {
+            ret += self.quality.taps() / 2 + 1;
+        }
+        ret
+    }
+    /// Produce the next output frame into `out`, pulling source frames from `buf` as needed.
+    ///
+    /// Returns the number of source frames the position advanced by.
+    #[inline(always)]
+    pub fn next_frame<S: SampleSource>(&mut self, buf: &mut S, out: &mut [f32]) -> u64 {
+        let taps = self.quality.taps();
+        let centre = taps / 2 - 1;
+        if !self.primed {
+            for ch in 0..self.channels {
+                let hist = &mut self.hist[ch * MAX_TAPS..(ch + 1) * MAX_TAPS];
+                for h in hist[..centre].iter_mut() {
+                    *h = 0.0;
+                }
+            }
+            for i in centre..taps {
+                for ch in 0..self.channels {
+                    self.hist[ch * MAX_TAPS + i] = buf.next_sample();
+                }
+            }
+            self.frac = 0.0;
+            self.primed = true;
+        }
+        for (ch, x) in out.iter_mut().enumerate().take(self.channels) {
+            *x = self.interpolate(&self.hist[ch * MAX_TAPS..(ch + 1) * MAX_TAPS], taps, centre);
+        }
+        self.frac += self.ratio;
+        let mut adv = 0;
+        while self.frac >= 1.0 {
+            self.frac -= 1.0;
+            for ch in 0..self.channels {
+                let hist = &mut self.hist[ch * MAX_TAPS..(ch + 1) * MAX_TAPS];
+                for i in 0..(taps - 1) {
+                    hist[i] = hist[i + 1];
+                }
+                hist[taps - 1] = buf.next_sample();
+            }
+            adv += 1;
+        }
+        adv
+    }
+    #[inline(always)]
+    fn interpolate(&self, h: &[f32], taps: usize, c: usize) -> f32 {
+        use self::ResampleQuality::*;
+        let f = self.frac as f32;
+        match self.quality {
+            Linear => h[c] + (h[c + 1] - h[c]) * f,
+            Cubic => {
+                let (p0, p1, p2, p3) = (h[c - 1], h[c], h[c + 1], h[c + 2]);
+                p1 + 0.5 * f * (p2 - p0 + f * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3
+                                              + f * (3.0 * (p1 - p2) + p3 - p0)))
+            },
+            Sinc => {
+                let kernel = self.kernel.as_ref().unwrap();
+                let pos = self.frac * SINC_PHASES as f64;
+                let phase = pos as usize;
+                let mix = (pos - phase as f64) as f32;
+                let row_a = &kernel[phase * MAX_TAPS..(phase + 1) * MAX_TAPS];
+                let row_b = &kernel[(phase + 1) * MAX_TAPS..(phase + 2) * MAX_TAPS];
+                let mut ret = 0.0;
+                for i in 0..taps {
+                    let k = row_a[i] + (row_b[i] - row_a[i]) * mix;
+                    ret += h[i] * k;
+                }
+                ret
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Routing the channels of a stream (or bus) to the engine's output channels and buses.
+//!
+//! Each stream has a routing matrix, made up of crosspoints: one of the stream's channels, going
+//! to one output channel (or one channel of a bus), at some gain. A source channel can go to any
+//! number of outputs (and many source channels can go to the same output), so one sound can be
+//! sent to several speakers at different levels.
+
+/// Somewhere audio can be sent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dest {
+    /// An output channel, by channel number.
+    Channel(usize),
+    /// A channel of a bus, by bus number and channel of that bus.
+    Bus(usize, usize)
+}
+impl From<usize> for Dest {
+    /// Channel numbers on their own refer to output channels.
+    fn from(ch: usize) -> Dest {
+        Dest::Channel(ch)
+    }
+}
+
+/// One crosspoint of a routing matrix.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Crosspoint {
+    /// The channel of the stream that audio comes from.
+    pub source: usize,
+    /// Where that audio goes to.
+    pub dest: Dest,
+    /// The gain applied to audio on its way through, as a multiplier.
+    pub gain: f32
+}
+
+/// A routing matrix, describing where each of a stream's channels is sent.
+///
+/// Set one on a stream with `Sender::set_routing()`. A stream with an empty routing matrix plays,
+/// but isn't heard anywhere.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Routing {
+    points: Vec<Crosspoint>
+}
+impl Routing {
+    /// Make a new, empty routing matrix.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Make a routing matrix that sends one source channel to one destination, at unity gain.
+    pub fn patch<D: Into<Dest>>(source: usize, dest: D) -> Self {
+        let mut ret = Self::new();
+        ret.set(source, dest, 1.0);
+        ret
+    }
+    /// Get all of this matrix's crosspoints.
+    pub fn points(&self) -> &[Crosspoint] {
+        &self.points
+    }
+    /// Get the gain of the crosspoint between a source channel and a destination, if there
+    /// is one.
+    pub fn gain<D: Into<Dest>>(&self, source: usize, dest: D) -> Option<f32> {
+        let dest = dest.into();
+        self.points.iter()
+            .find(|p| p.source == source && p.dest == dest)
+            .map(|p| p.gain)
+    }
+    /// Get the destinations a source channel is sent to, in the order they were added.
+    pub fn dests<'a>(&'a self, source: usize) -> impl Iterator<Item=Dest> + 'a {
+        self.points.iter()
+            .filter(move |p| p.source == source)
+            .map(|p| p.dest)
+    }
+    /// Send a source channel to a destination at a given gain, replacing the gain of any
+    /// existing crosspoint between the two.
+    pub fn set<D: Into<Dest>>(&mut self, source: usize, dest: D, gain: f32) {
+        let dest = dest.into();
+        if let Some(p) = self.points.iter_mut().find(|p| p.source == source && p.dest == dest) {
+            p.gain = gain;
+            return;
+        }
+        self.points.push(Crosspoint { source, dest, gain });
+    }
+    /// Remove the crosspoint between a source channel and a destination.
+    ///
+    /// Returns whether there was one to remove.
+    pub fn remove<D: Into<Dest>>(&mut self, source: usize, dest: D) -> bool {
+        let dest = dest.into();
+        let len = self.points.len();
+        self.points.retain(|p| !(p.source == source && p.dest == dest));
+        self.points.len() != len
+    }
+    /// Remove every crosspoint from a source channel, so that it isn't sent anywhere.
+    pub fn clear_source(&mut self, source: usize) {
+        self.points.retain(|p| p.source != source);
+    }
+}
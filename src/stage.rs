@@ -0,0 +1,191 @@
+//! Output stages, applied to each channel once its mix is complete.
+//!
+//! Every channel's audio is clamped to [-1.0, 1.0] right before it leaves the engine. An output
+//! stage runs just before that, to keep the mix from getting that far (see `OutputStage`).
+
+use std::time::Duration;
+use super::ONE_SECOND_IN_NANOSECONDS;
+
+/// How a channel's audio is processed after mixing.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputStage {
+    /// No processing; anything outside [-1.0, 1.0] is hard clipped.
+    None,
+    /// Soft clipping: audio is untouched up to half of full scale, and is smoothly squashed
+    /// above that, approaching (but never reaching) full scale.
+    SoftClip,
+    /// A look-ahead brickwall limiter, which never lets audio exceed `ceiling`.
+    ///
+    /// Delays the channel's audio by `lookahead`, which is also how long the limiter takes to
+    /// turn the gain down ahead of a peak. `release` is roughly how long it takes to recover
+    /// afterwards.
+    Limiter {
+        ceiling: f32,
+        lookahead: Duration,
+        release: Duration
+    }
+}
+impl OutputStage {
+    /// A limiter with a given ceiling, 5ms of look-ahead and a 50ms release.
+    pub fn limiter(ceiling: f32) -> Self {
+        OutputStage::Limiter {
+            ceiling,
+            lookahead: Duration::from_millis(5),
+            release: Duration::from_millis(50)
+        }
+    }
+}
+impl Default for OutputStage {
+    fn default() -> Self {
+        OutputStage::None
+    }
+}
+
+fn duration_samples(dur: Duration, sample_rate: u64) -> f64 {
+    let nanos = dur.as_secs() * ONE_SECOND_IN_NANOSECONDS + dur.subsec_nanos() as u64;
+    nanos as f64 * sample_rate as f64 / ONE_SECOND_IN_NANOSECONDS as f64
+}
+
+/// The state of a look-ahead limiter.
+///
+/// The gain needed to keep each sample under the ceiling is smoothed for release, held at its
+/// minimum over the look-ahead window, and then averaged over the window again. Every gain that
+/// goes into the average for a sample is at most the gain that sample needs, so the limiter can
+/// never overshoot, and gain changes are spread out over the whole window.
+struct Limiter {
+    ceiling: f32,
+    /// How much of the way back to unity gain to go each sample.
+    release: f32,
+    /// The look-ahead window, in samples (at least 1).
+    len: usize,
+    /// The release-smoothed gain.
+    env: f32,
+    /// Delay line for the audio, of `len` samples (delaying it by `len - 1`).
+    delay: Box<[f32]>,
+    delay_pos: usize,
+    /// Sliding window minimum of `env`: a ring buffer of (sample number, gain) pairs, with
+    /// increasing gains, and room for `len + 1` of them.
+    hold_idx: Box<[u64]>,
+    hold_val: Box<[f32]>,
+    hold_head: usize,
+    hold_len: usize,
+    /// The sample number of the next sample.
+    n: u64,
+    /// Moving average of the held gain.
+    avg: Box<[f32]>,
+    avg_pos: usize,
+    avg_sum: f64
+}
+impl Limiter {
+    fn new(ceiling: f32, lookahead: Duration, release: Duration, sample_rate: u64) -> Self {
+        let len = (duration_samples(lookahead, sample_rate).round() as usize).max(1);
+        let release_samples = duration_samples(release, sample_rate).max(1.0);
+        Limiter {
+            ceiling: ceiling.abs(),
+            release: (1.0 - (-1.0 / release_samples).exp()) as f32,
+            len,
+            env: 1.0,
+            delay: vec![0.0; len].into_boxed_slice(),
+            delay_pos: 0,
+            hold_idx: vec![0; len + 1].into_boxed_slice(),
+            hold_val: vec![0.0; len + 1].into_boxed_slice(),
+            hold_head: 0,
+            hold_len: 0,
+            n: 0,
+            avg: vec![1.0; len].into_boxed_slice(),
+            avg_pos: 0,
+            avg_sum: len as f64
+        }
+    }
+    #[inline(always)]
+    fn process(&mut self, buf: &mut [f32]) {
+        let len = self.len;
+        let cap = len + 1;
+        for x in buf.iter_mut() {
+            let level = x.abs();
+            let target = if level > self.ceiling { self.ceiling / level } else { 1.0 };
+            self.env = target.min(self.env + self.release * (1.0 - self.env));
+            // Update the sliding window minimum.
+            while self.hold_len > 0 && self.hold_val[(self.hold_head + self.hold_len - 1) % cap] >= self.env {
+                self.hold_len -= 1;
+            }
+            let tail = (self.hold_head + self.hold_len) % cap;
+            self.hold_idx[tail] = self.n;
+            self.hold_val[tail] = self.env;
+            self.hold_len += 1;
+            if self.hold_idx[self.hold_head] + (len as u64) <= self.n {
+                self.hold_head = (self.hold_head + 1) % cap;
+                self.hold_len -= 1;
+            }
+            let hold = self.hold_val[self.hold_head];
+            self.n += 1;
+            // Average it.
+            self.avg_sum += (hold - self.avg[self.avg_pos]) as f64;
+            self.avg[self.avg_pos] = hold;
+            self.avg_pos = (self.avg_pos + 1) % len;
+            let gain = (self.avg_sum / len as f64) as f32;
+            // Delay the audio to line up with the gain.
+            self.delay[self.delay_pos] = *x;
+            self.delay_pos = (self.delay_pos + 1) % len;
+            let out = self.delay[self.delay_pos] * gain;
+            *x = out.max(-self.ceiling).min(self.ceiling);
+        }
+    }
+}
+
+enum StageState {
+    None,
+    SoftClip,
+    Limiter(Limiter)
+}
+
+/// An `OutputStage`, along with whatever state it needs on the audio thread.
+pub struct ChannelStage {
+    stage: OutputStage,
+    state: StageState
+}
+impl ChannelStage {
+    /// Set up an output stage for a given sample rate. Only ever called on the main thread,
+    /// as this can allocate.
+    pub(crate) fn new(stage: OutputStage, sample_rate: u64) -> Self {
+        let state = match stage {
+            OutputStage::None => StageState::None,
+            OutputStage::SoftClip => StageState::SoftClip,
+            OutputStage::Limiter { ceiling, lookahead, release } => {
+                StageState::Limiter(Limiter::new(ceiling, lookahead, release, sample_rate))
+            }
+        };
+        ChannelStage { stage, state }
+    }
+    /// An `OutputStage::None`, which (unlike `new()`) is fine to make on the audio thread.
+    #[inline(always)]
+    pub(crate) fn none() -> Self {
+        ChannelStage { stage: OutputStage::None, state: StageState::None }
+    }
+    /// Get the `OutputStage` this was set up from.
+    pub fn stage(&self) -> OutputStage {
+        self.stage
+    }
+    /// Run one period of audio through the stage, and clamp it to [-1.0, 1.0].
+    #[inline(always)]
+    pub(crate) fn process(&mut self, buf: &mut [f32]) {
+        match self.state {
+            StageState::None => {},
+            StageState::SoftClip => {
+                const KNEE: f32 = 0.5;
+                for x in buf.iter_mut() {
+                    let level = x.abs();
+                    if level > KNEE {
+                        let squashed = KNEE + (1.0 - KNEE) * ((level - KNEE) / (1.0 - KNEE)).tanh();
+                        *x = if *x < 0.0 { -squashed } else { squashed };
+                    }
+                }
+            },
+            StageState::Limiter(ref mut l) => l.process(buf)
+        }
+        for x in buf.iter_mut() {
+            if *x > 1.0 { *x = 1.0; }
+            if *x < -1.0 { *x = -1.0; }
+        }
+    }
+}
@@ -2,13 +2,19 @@
 
 use parking_lot::{Mutex, Condvar};
 use std::sync::Arc;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::*;
 use std::time::{Duration, Instant};
 use bounded_spsc_queue::{Producer, Consumer};
 use super::CONTROL_BUFFER_SIZE;
 use bounded_spsc_queue;
 use uuid::Uuid;
+use routing::Routing;
+use param::Parameter;
 
-pub use thread::Player;
+pub use thread::{Player, Tap, Capture, DeviceBus, Seek, Loop};
+pub use stage::ChannelStage;
+pub use transaction::Transaction;
 
 /// A message from the audio thread.
 pub enum AudioThreadMessage {
@@ -18,10 +24,24 @@ pub enum AudioThreadMessage {
     PlayerRejected(Player),
     /// This player was removed on account of not being `alive`.
     PlayerRemoved(Player),
-    /// The player with a given `Uuid` has an invalid output patch. Playback has been stopped.
+    /// The capture with a given `Uuid` (belonging to a `Receiver` or a recording) was
+    /// successfully added.
+    CaptureAdded(Uuid),
+    /// This capture was rejected due to there being more than `MAX_CHANS` of them.
+    CaptureRejected(Capture),
+    /// This capture was removed on account of not being `alive`.
+    CaptureRemoved(Capture),
+    /// This bus was removed, with `EngineContext::remove_bus()`.
+    BusRemoved(DeviceBus),
+    /// This output stage was replaced, or its channel was removed.
+    OutputStageRemoved(ChannelStage),
+    /// This bounce tap was replaced or removed, and is no longer being written to.
+    TapRemoved(Tap),
+    /// The player with a given `Uuid` has an invalid output patch (i.e. a crosspoint in its
+    /// routing matrix to or from a channel that doesn't exist). Playback has been stopped.
     ///
-    /// To resume playback, you MUST change the output patch to a valid channel
-    /// number, and call `set_active(true)`.
+    /// To resume playback, you MUST change the routing to only use valid channel
+    /// numbers, and call `set_active(true)`.
     PlayerInvalidOutpatch(Uuid),
     /// The player with a given `Uuid`'s buffer is half full.
     ///
@@ -34,6 +54,56 @@ pub enum AudioThreadMessage {
     /// refill the buffer and your audio has now stopped. In the latter case, you OUGHT TO refill the
     /// buffer.
     PlayerBufEmpty(Uuid),
+    /// The player with a given `Uuid` has seeked (see `Sender::seek()`): it's now playing from
+    /// its new buffer, which starts at the given position, in frames.
+    PlayerSeeked(Uuid, u64),
+    /// This seek, which holds a player's buffer from before it seeked, is no longer being
+    /// played from.
+    SeekBufferRemoved(Box<Seek>),
+    /// This transaction has been applied (see `EngineContext::submit()`). It now holds whatever
+    /// its changes replaced, to be deallocated.
+    TransactionApplied(Transaction),
+    /// This transaction was rejected, because there were already `MAX_PENDING_TRANSACTIONS`
+    /// transactions waiting for their time to come. None of it has been applied.
+    TransactionRejected(Transaction),
+    /// The transport group with a given ID has seeked (see `EngineContext::group_seek()`).
+    ///
+    /// This gives back the seeks that were sent to the audio thread, to be deallocated. Any
+    /// that are still there weren't for a member of the group, and went unused.
+    GroupSeeked(u64, Vec<(Uuid, Option<Box<Seek>>)>),
+    /// The player with a given `Uuid` has got to the end of its loop region, and jumped back
+    /// to the start of it. The number is how many times it's done this so far.
+    PlayerLooped(Uuid, u32),
+    /// This loop region was replaced, or was set too late to be played (see `Sender::set_loop()`).
+    LoopRemoved(Box<Loop>),
+    /// This routing matrix was replaced (see `Sender::set_routing()` and `Bus::set_routing()`),
+    /// and is no longer being used.
+    RoutingRemoved(Box<Routing>),
+    /// This volume was replaced (see `Sender::set_volume()`, `Sender::set_master_volume()` and
+    /// `Bus::set_volume()`), and is no longer being used.
+    VolumeRemoved(Box<Parameter<f32>>),
+    /// The player with a given `Uuid` was stopped by the audio thread, as requested with
+    /// `Sender::stop_at()`, `Sender::stop_at_position()` or `Sender::fade_out_and_stop()`.
+    ///
+    /// If the stop was a kill, this comes before the `PlayerRemoved` message.
+    PlayerStopped(Uuid),
+    /// A fade (or envelope) on the volume of the player with a given `Uuid` has finished. The
+    /// fade is identified by its `id_ptr`.
+    ///
+    /// This is sent once per fade, at the end of the period in which it completes - even if the
+    /// player is paused or inactive.
+    FadeComplete(Uuid, Arc<()>),
+    /// A fade (or envelope) on a master volume has finished. The fade is identified by its
+    /// `id_ptr`.
+    ///
+    /// As with `FadeComplete`, this is sent once per fade, even if the master volume is shared
+    /// between several players (see `EngineContext::new_sender_with_master()`).
+    MasterFadeComplete(Arc<()>),
+    /// A fade (or envelope) on the volume of the bus with a given bus number has finished.
+    BusFadeComplete(usize, Arc<()>),
+    /// The audio thread handled as many commands as it's allowed to in one period (see
+    /// `EngineContext::set_command_budget()`), and left this many for the next period.
+    CommandBudgetExceeded(usize),
     /// The audio thread has experienced an under- or over- run.
     ///
     /// This REALLY SHOULD NOT happen under normal circumstances. If your sample rate and buffer size
@@ -43,6 +113,91 @@ pub enum AudioThreadMessage {
     Xrun
 }
 
+/// A value shared between the main thread and the audio thread, like a stream's volume or routing
+/// matrix.
+///
+/// Only the audio thread changes the value in use. The main thread leaves new values in a slot
+/// for it to pick up (keeping a copy of the latest one to read back), and the audio thread sends
+/// the values it replaces back to the main thread to be deallocated.
+pub struct SharedValue<T> {
+    /// The value in use. Only ever changed by the audio thread.
+    current: AtomicPtr<T>,
+    /// A value waiting for the audio thread to pick it up, or null.
+    pending: AtomicPtr<T>,
+    /// The value most recently set on the main thread.
+    copy: Mutex<T>
+}
+impl<T> SharedValue<T> where T: Clone {
+    pub(crate) fn new(val: T) -> Self {
+        SharedValue {
+            current: AtomicPtr::new(Box::into_raw(Box::new(val.clone()))),
+            pending: AtomicPtr::new(::std::ptr::null_mut()),
+            copy: Mutex::new(val)
+        }
+    }
+    /// Get the value most recently set, which the audio thread might not have picked up yet.
+    pub(crate) fn get(&self) -> T {
+        self.copy.lock().clone()
+    }
+    /// Set a new value, for the audio thread to pick up at the start of its next period.
+    pub(crate) fn set(&self, val: Box<T>) {
+        let mut copy = self.copy.lock();
+        *copy = (*val).clone();
+        // Whatever was in the slot was never picked up, so it's ours to deallocate.
+        self.queue(val);
+    }
+    /// Update the copy of the value, without touching the slot (for values that get to the
+    /// audio thread some other way, i.e. in a transaction).
+    pub(crate) fn set_copy(&self, val: &T) {
+        *self.copy.lock() = val.clone();
+    }
+}
+impl<T> SharedValue<T> {
+    /// Put a value in the slot, returning the one that was there before (if any), which was
+    /// never used.
+    pub(crate) fn queue(&self, val: Box<T>) -> Option<Box<T>> {
+        let old = self.pending.swap(Box::into_raw(val), AcqRel);
+        if old.is_null() {
+            None
+        }
+        else {
+            Some(unsafe { Box::from_raw(old) })
+        }
+    }
+    /// Start using the value in the slot, if there is one, returning the value it replaces.
+    ///
+    /// MUST only be called from the audio thread.
+    pub(crate) fn pick_up(&self) -> Option<Box<T>> {
+        let new = self.pending.swap(::std::ptr::null_mut(), AcqRel);
+        if new.is_null() {
+            return None;
+        }
+        let old = self.current.swap(new, AcqRel);
+        Some(unsafe { Box::from_raw(old) })
+    }
+    /// Get the value in use.
+    ///
+    /// # Safety
+    ///
+    /// MUST only be called from the audio thread, and the reference MUST NOT be held across a
+    /// call to `pick_up()`, which may replace the value.
+    pub(crate) unsafe fn current(&self) -> &T {
+        &*self.current.load(Acquire)
+    }
+}
+impl<T> Drop for SharedValue<T> {
+    fn drop(&mut self) {
+        let cur = self.current.swap(::std::ptr::null_mut(), AcqRel);
+        let pending = self.pending.swap(::std::ptr::null_mut(), AcqRel);
+        unsafe {
+            let _: Box<T> = Box::from_raw(cur);
+            if !pending.is_null() {
+                let _: Box<T> = Box::from_raw(pending);
+            }
+        }
+    }
+}
+
 /// A commmunication channel to receive messages from the audio thread.
 pub struct AudioThreadHandle {
     inner: Arc<(Mutex<()>, Condvar)>,
@@ -2,30 +2,73 @@
 
 use sqa_jack::*;
 use arrayvec::ArrayVec;
-use super::{MAX_PLAYERS, MAX_CHANS, ONE_SECOND_IN_NANOSECONDS};
-use bounded_spsc_queue::Consumer;
+use super::{MAX_PLAYERS, MAX_CHANS, MAX_PENDING_TRANSACTIONS, MAX_SENDER_CHANNELS, MAX_BUSES, MAX_BUFFER_SIZE, ONE_SECOND_IN_NANOSECONDS, SEEK_CROSSFADE_TIME, STOP_DECLICK_TIME};
+use bounded_spsc_queue::{Consumer, Producer};
 use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, AtomicPtr};
 use std::sync::atomic::Ordering::*;
 use std::sync::Arc;
 use uuid::Uuid;
-use time;
-use sync::AudioThreadSender;
+use sync::{AudioThreadSender, SharedValue};
 use sync::AudioThreadMessage::*;
 use param::Parameter;
+use clock::Clock;
+use resample::{Resampler, SampleSource};
+use routing::{Dest, Routing};
+use stage::ChannelStage;
+use meter::{MeterBank, PlayerMeters};
+use group::GroupAction;
+use transaction::{Op, Transaction};
 
-/// Holds data about one mono channel of audio, to be played back on the audio thread.
+/// Holds data about one stream of audio (with one or more interleaved channels), to be played
+/// back on the audio thread.
 pub struct Player {
     pub buf: Consumer<f32>,
     pub sample_rate: u64,
+    pub channels: usize,
     pub start_time: Arc<AtomicU64>,
     pub position: Arc<AtomicU64>,
     pub active: Arc<AtomicBool>,
     pub alive: Arc<AtomicBool>,
     pub kill_when_empty: Arc<AtomicBool>,
-    pub output_patch: Arc<AtomicUsize>,
-    pub volume: Arc<AtomicPtr<Parameter<f32>>>,
-    pub master_vol: Arc<AtomicPtr<Parameter<f32>>>,
+    /// Whether the player is paused (i.e. holding its position until it's resumed).
+    pub paused: Arc<AtomicBool>,
+    /// Whether the player was paused last time we looked, so its start time needs fixing up.
+    pub was_paused: bool,
+    /// The ID of the transport group the player is in (0 if it isn't in one).
+    pub group: Arc<AtomicU64>,
+    /// The time to stop the player at (`u64::MAX` if it isn't being stopped).
+    pub stop_time: Arc<AtomicU64>,
+    /// The position to stop the player at, in frames (`u64::MAX` if it isn't being stopped).
+    pub stop_position: Arc<AtomicU64>,
+    /// Whether to ramp down to silence over `STOP_DECLICK_TIME` before stopping.
+    pub stop_declick: Arc<AtomicBool>,
+    /// Whether stopping the player kills it, rather than just deactivating it.
+    pub kill_on_stop: Arc<AtomicBool>,
+    /// Where each of the stream's channels is sent.
+    pub routing: Arc<SharedValue<Routing>>,
+    pub volume: Arc<SharedValue<Parameter<f32>>>,
+    /// Shared between every stream made with `EngineContext::new_sender_with_master()`.
+    pub master_vol: Arc<SharedValue<Parameter<f32>>>,
     pub uuid: Uuid,
+    /// Converts from `sample_rate` to the engine's sample rate. Unused if they're the same.
+    pub resampler: Resampler,
+    /// A seek waiting to be picked up, or null.
+    pub seek: Arc<AtomicPtr<Seek>>,
+    /// The last seek, holding the old buffer, while it's being crossfaded out.
+    pub seek_tail: Option<Box<Seek>>,
+    /// The index (in samples, not counting loop repeats) of the next sample in the stream.
+    pub read_pos: u64,
+    /// A loop region waiting to be picked up, or null.
+    pub loop_pending: Arc<AtomicPtr<Loop>>,
+    /// The current loop region, if there is one.
+    pub lp: Option<Box<Loop>>,
+    /// Whether to leave the loop region the next time its end is reached.
+    pub devamp: Arc<AtomicBool>,
+    pub meters: Arc<PlayerMeters>,
+    /// This period's peak level for each channel, if metering.
+    pub meter_peak: [f32; MAX_SENDER_CHANNELS],
+    /// This period's sum of squared samples for each channel, if metering.
+    pub meter_sum_squares: [f32; MAX_SENDER_CHANNELS],
     pub half_sent: bool,
     pub empty_sent: bool
 }
@@ -33,39 +76,336 @@ impl Drop for Player {
     fn drop(&mut self) {
         self.active.store(false, Relaxed);
         self.alive.store(false, Relaxed);
+        let seekp = self.seek.swap(::std::ptr::null_mut(), AcqRel);
+        if !seekp.is_null() {
+            unsafe {
+                let _: Box<Seek> = Box::from_raw(seekp);
+            }
+        }
+        let loopp = self.loop_pending.swap(::std::ptr::null_mut(), AcqRel);
+        if !loopp.is_null() {
+            unsafe {
+                let _: Box<Loop> = Box::from_raw(loopp);
+            }
+        }
+    }
+}
+
+/// A loop region, set up on the main thread by `Sender::set_loop()`.
+pub struct Loop {
+    /// The audio in the loop region, as interleaved frames.
+    pub buf: Box<[f32]>,
+    /// The frame of the stream that the loop region starts at.
+    pub start: u64,
+    /// How many times to jump back to the start of the region (`u32::MAX` for forever).
+    pub loops: u32,
+    /// How many more times to jump back to the start of the region.
+    pub loops_left: u32,
+    /// How many times we've jumped back to the start of the region.
+    pub wraps: u32,
+    /// How many of those jumps have been reported with `PlayerLooped`.
+    pub wraps_sent: u32,
+    /// Whether playback has reached the loop region yet.
+    pub entered: bool,
+    /// The next sample of `buf` to play, once the loop region has been entered. Once the loop's
+    /// over, this is `buf.len()`.
+    pub cursor: usize
+}
+impl Loop {
+    /// Whether the loop region has been played through for the last time.
+    #[inline(always)]
+    fn done(&self) -> bool {
+        self.entered && self.cursor == self.buf.len()
+    }
+    /// Go back to before the loop region, or skip it entirely if `read_pos` is past its start.
+    #[inline(always)]
+    fn rewind(&mut self, read_pos: u64, chans: usize) {
+        self.loops_left = self.loops;
+        if read_pos <= self.start * chans as u64 {
+            self.entered = false;
+            self.cursor = 0;
+        }
+        else {
+            self.entered = true;
+            self.cursor = self.buf.len();
+        }
+    }
+}
+
+/// Where a player's samples come from: its buffer, with its loop region (if any) spliced in.
+struct PlayerSource<'a> {
+    buf: &'a Consumer<f32>,
+    lp: Option<&'a mut Box<Loop>>,
+    devamp: bool,
+    read_pos: &'a mut u64,
+    chans: usize
+}
+impl<'a> PlayerSource<'a> {
+    /// The number of samples that can be pulled before running out.
+    #[inline(always)]
+    fn available(&self) -> usize {
+        let queued = self.buf.size();
+        let lp = match self.lp {
+            Some(ref lp) if !lp.done() => lp,
+            _ => return queued
+        };
+        let len = lp.buf.len();
+        let region = if self.devamp {
+            len - lp.cursor
+        }
+        else if lp.loops_left == ::std::u32::MAX {
+            ::std::usize::MAX
+        }
+        else {
+            (len - lp.cursor).saturating_add((lp.loops_left as usize).saturating_mul(len))
+        };
+        if !lp.entered {
+            let to_start = (lp.start * self.chans as u64).saturating_sub(*self.read_pos) as usize;
+            if queued < to_start {
+                return queued;
+            }
+        }
+        queued.saturating_add(region)
+    }
+    /// Skip up to `n` samples, returning how many were skipped.
+    #[inline(always)]
+    fn skip(&mut self, n: usize) -> usize {
+        if self.lp.as_ref().map(|lp| lp.done()).unwrap_or(true) {
+            let ret = self.buf.skip_n(n);
+            *self.read_pos += ret as u64;
+            return ret;
+        }
+        let n = ::std::cmp::min(n, self.available());
+        for _ in 0..n {
+            self.next_sample();
+        }
+        n
+    }
+}
+impl<'a> SampleSource for PlayerSource<'a> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> f32 {
+        if let Some(ref mut lp) = self.lp {
+            if !lp.entered && *self.read_pos == lp.start * self.chans as u64 {
+                lp.entered = true;
+            }
+            if lp.entered && lp.cursor < lp.buf.len() {
+                let x = lp.buf[lp.cursor];
+                lp.cursor += 1;
+                *self.read_pos += 1;
+                if lp.cursor == lp.buf.len() && lp.loops_left > 0 && !self.devamp {
+                    if lp.loops_left != ::std::u32::MAX {
+                        lp.loops_left -= 1;
+                    }
+                    lp.cursor = 0;
+                    *self.read_pos = lp.start * self.chans as u64;
+                    lp.wraps += 1;
+                }
+                return x;
+            }
+        }
+        match self.buf.try_pop() {
+            Some(x) => {
+                *self.read_pos += 1;
+                x
+            },
+            None => 0.0
+        }
+    }
+}
+
+impl Player {
+    /// Stop the player, as scheduled with `Sender::stop_at()` or `Sender::stop_at_position()`.
+    #[inline(always)]
+    fn stop(&mut self, sender: &mut AudioThreadSender) {
+        self.stop_time.store(::std::u64::MAX, Relaxed);
+        self.stop_position.store(::std::u64::MAX, Relaxed);
+        if self.kill_on_stop.load(Relaxed) {
+            self.alive.store(false, Relaxed);
+        }
+        self.active.store(false, Relaxed);
+        sender.send(PlayerStopped(self.uuid));
+    }
+    /// Swap in the buffer from a seek (see `Sender::seek()`), crossfading out of the old one if
+    /// the player is playing.
+    #[inline(always)]
+    fn apply_seek(&mut self, mut seek: Box<Seek>, time: u64, sample_rate: u64, sender: &mut AudioThreadSender) {
+        // The old buffer doesn't have the loop region in it, so it can only be crossfaded out of
+        // up to where the region starts (and not at all once the region's been entered).
+        let old_frames_left = match self.lp {
+            Some(ref lp) if !lp.done() && lp.entered => 0,
+            Some(ref lp) if !lp.done() => {
+                let frames = (lp.start * self.channels as u64).saturating_sub(self.read_pos) / self.channels as u64;
+                frames * sample_rate / self.sample_rate
+            },
+            _ => ::std::u64::MAX
+        };
+        ::std::mem::swap(&mut self.buf, &mut seek.buf);
+        ::std::mem::swap(&mut self.resampler, &mut seek.resampler);
+        self.half_sent = false;
+        self.empty_sent = false;
+        self.read_pos = seek.position * self.channels as u64;
+        if let Some(ref mut lp) = self.lp {
+            lp.rewind(self.read_pos, self.channels);
+        }
+        let start_time = self.start_time.load(Relaxed);
+        if start_time <= time {
+            // Pretend we started playing long enough ago to be at the new position.
+            let pos_ns = seek.position * ONE_SECOND_IN_NANOSECONDS / self.sample_rate;
+            self.start_time.store(time.saturating_sub(pos_ns), Relaxed);
+            self.position.store(seek.position, Relaxed);
+        }
+        sender.send(PlayerSeeked(self.uuid, seek.position));
+        if let Some(old) = self.seek_tail.take() {
+            sender.send(SeekBufferRemoved(old));
+        }
+        if self.active.load(Relaxed) && start_time <= time && old_frames_left > 0 {
+            let xfade_len = ::std::cmp::min(SEEK_CROSSFADE_TIME * sample_rate / ONE_SECOND_IN_NANOSECONDS, old_frames_left);
+            seek.xfade_len = ::std::cmp::max(xfade_len as usize, 1);
+            seek.xfade_left = seek.xfade_len;
+            self.seek_tail = Some(seek);
+        }
+        else {
+            sender.send(SeekBufferRemoved(seek));
+        }
     }
 }
 
-pub enum AudioThreadCommand {
+/// A seek, set up on the main thread by `Sender::seek()`.
+///
+/// When the audio thread picks it up, it swaps the buffer and resampler in here with the
+/// player's, so from then on this holds the old ones (to crossfade out of, if the player was
+/// playing) until it's sent back to be deallocated.
+pub struct Seek {
+    pub buf: Consumer<f32>,
+    pub resampler: Resampler,
+    /// The position, in frames, that the new buffer starts at.
+    pub position: u64,
+    /// The length of the crossfade, in frames.
+    pub xfade_len: usize,
+    /// The number of frames of crossfade left to go.
+    pub xfade_left: usize
+}
+
+/// A tap on the output of a set of channels, used for bouncing.
+pub struct Tap {
+    /// The channel numbers to tap.
+    pub chans: ArrayVec<[usize; MAX_CHANS]>,
+    /// The buffer to push interleaved frames of audio to.
+    pub buf: Producer<f32>,
+    /// Whether the tap has had to drop audio because the buffer was full.
+    pub overflowed: Arc<AtomicBool>
+}
+
+/// Captures audio from a set of input channels, used by `Receiver`s and recordings.
+pub struct Capture {
+    /// The input channel numbers to capture.
+    pub chans: ArrayVec<[usize; MAX_CHANS]>,
+    /// The buffer to push interleaved frames of audio to.
+    pub buf: Producer<f32>,
+    pub active: Arc<AtomicBool>,
+    pub alive: Arc<AtomicBool>,
+    /// Whether the capture has had to drop audio because the buffer was full.
+    pub overflowed: Arc<AtomicBool>,
+    /// The time the first frame was captured at (`u64::MAX` if none have been yet).
+    pub start_time: Arc<AtomicU64>,
+    /// The number of frames captured so far.
+    pub frames: Arc<AtomicU64>,
+    pub uuid: Uuid
+}
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.active.store(false, Relaxed);
+        self.alive.store(false, Relaxed);
+    }
+}
+
+/// A bus in the device context.
+pub struct DeviceBus {
+    pub channels: usize,
+    /// Scratch space to sum audio into: `MAX_BUFFER_SIZE` frames for each channel, one channel
+    /// after another.
+    pub buf: Box<[f32]>,
+    pub volume: Arc<SharedValue<Parameter<f32>>>,
+    /// Where each of the bus's channels is sent.
+    pub routing: Arc<SharedValue<Routing>>
+}
+
+pub enum AudioThreadCommand<P> {
     AddPlayer(Player),
-    AddChannel(JackPort),
-    RemoveChannel(usize)
+    AddChannel(P),
+    RemoveChannel(usize),
+    AddInput(P),
+    RemoveInput(usize),
+    AddCapture(Capture),
+    SetOutputStage(usize, ChannelStage),
+    AddBus(DeviceBus),
+    RemoveBus(usize),
+    SetTap(Option<Tap>),
+    GroupTransport(u64, GroupAction),
+    Transaction(Transaction)
+}
+
+/// Something that can hand out port buffers for one run of the audio callback.
+///
+/// Implemented for JACK's `JackCallbackContext`, and for the offline renderer.
+pub trait CallbackContext {
+    /// The type of port this context hands out buffers for.
+    type Port;
+    /// Returns the number of frames that must be processed in this callback.
+    fn nframes(&self) -> u32;
+    /// Gets a pointer to the buffer of a port, if the port is valid.
+    ///
+    /// The pointer aliases memory owned by the context, so dereferencing it is up to the caller:
+    /// callers MUST NOT hold more than one reference to the same port's buffer at once, and MUST
+    /// drop it before the callback returns.
+    fn get_port_buffer(&self, port: &Self::Port) -> Option<*mut [f32]>;
+}
+impl CallbackContext for JackCallbackContext {
+    type Port = JackPort;
+    #[inline(always)]
+    fn nframes(&self) -> u32 {
+        JackCallbackContext::nframes(self)
+    }
+    #[inline(always)]
+    fn get_port_buffer(&self, port: &JackPort) -> Option<*mut [f32]> {
+        JackCallbackContext::get_port_buffer(self, port).map(|b| b as *mut [f32])
+    }
 }
 
 /// A channel in the device context.
-pub struct DeviceChannel {
-    /// The `JackPort` of the channel.
-    port: JackPort,
-    /// The time that this channel was last written to.
-    /// Used to zero out the channel if it wasn't written to this callback.
-    written_t: u64,
-    /// The time that this channel was last zeroed out.
-    zeroed_t: u64
+pub struct DeviceChannel<P> {
+    /// The port of the channel.
+    port: P,
+    /// The output stage the channel's audio goes through.
+    stage: ChannelStage
 }
 
 /// Audio thread handler.
-pub struct DeviceContext {
+pub struct DeviceContext<P> {
     pub players: ArrayVec<[Player; MAX_PLAYERS]>,
-    pub chans: ArrayVec<[Option<DeviceChannel>; MAX_CHANS]>,
+    pub chans: ArrayVec<[Option<DeviceChannel<P>>; MAX_CHANS]>,
     pub holes: ArrayVec<[usize; MAX_CHANS]>,
-    pub control: Consumer<AudioThreadCommand>,
+    pub inputs: ArrayVec<[Option<P>; MAX_CHANS]>,
+    pub input_holes: ArrayVec<[usize; MAX_CHANS]>,
+    pub captures: ArrayVec<[Capture; MAX_CHANS]>,
+    pub buses: ArrayVec<[Option<DeviceBus>; MAX_BUSES]>,
+    pub bus_holes: ArrayVec<[usize; MAX_BUSES]>,
+    /// Transactions waiting for their time to come, in the order they were submitted.
+    pub pending: ArrayVec<[Transaction; MAX_PENDING_TRANSACTIONS]>,
+    pub control: Consumer<AudioThreadCommand<P>>,
     pub length: Arc<AtomicUsize>,
+    /// The most commands to handle in one period.
+    pub command_budget: Arc<AtomicUsize>,
+    pub tap: Option<Tap>,
+    pub clock: Arc<dyn Clock>,
     pub(crate) sender: AudioThreadSender,
+    pub(crate) meters: Arc<MeterBank>,
     pub sample_rate: u64
 }
-impl DeviceContext {
+impl<P> DeviceContext<P> {
     #[inline(always)]
-    fn handle(&mut self, cmd: AudioThreadCommand) {
+    fn handle(&mut self, cmd: AudioThreadCommand<P>, time: u64) {
         match cmd {
             AudioThreadCommand::AddPlayer(p) => {
                 let uu = p.uuid;
@@ -81,61 +421,378 @@ impl DeviceContext {
             },
             AudioThreadCommand::AddChannel(p) => {
                 /* NOTE: This code must mirror the code in lib.rs */
-                let ch = DeviceChannel { port: p, written_t: 0, zeroed_t: 0 };
+                let ch = DeviceChannel { port: p, stage: ChannelStage::none() };
                 if let Some(ix) = self.holes.remove(0) {
                     self.chans[ix] = Some(ch);
+                    self.meters.chans[ix].reset();
                 }
                 else {
+                    self.meters.chans[self.chans.len()].reset();
                     self.chans.push(Some(ch));
                 }
             },
             AudioThreadCommand::RemoveChannel(ch) => {
                 /* NOTE: This code must mirror the code in lib.rs */
                 self.chans.push(None);
-                self.chans.swap_remove(ch);
+                if let Some(Some(old)) = self.chans.swap_remove(ch) {
+                    self.sender.send(OutputStageRemoved(old.stage));
+                }
                 self.holes.push(ch);
             },
+            AudioThreadCommand::SetOutputStage(ch, stage) => {
+                match self.chans.get_mut(ch) {
+                    Some(&mut Some(ref mut ch)) => {
+                        let old = ::std::mem::replace(&mut ch.stage, stage);
+                        self.sender.send(OutputStageRemoved(old));
+                    },
+                    _ => self.sender.send(OutputStageRemoved(stage))
+                }
+            },
+            AudioThreadCommand::AddInput(p) => {
+                /* NOTE: This code must mirror the code in lib.rs */
+                if let Some(ix) = self.input_holes.remove(0) {
+                    self.inputs[ix] = Some(p);
+                }
+                else {
+                    self.inputs.push(Some(p));
+                }
+            },
+            AudioThreadCommand::RemoveInput(ch) => {
+                /* NOTE: This code must mirror the code in lib.rs */
+                self.inputs.push(None);
+                self.inputs.swap_remove(ch);
+                self.input_holes.push(ch);
+            },
+            AudioThreadCommand::AddCapture(c) => {
+                let uu = c.uuid;
+                if let Some(c) = self.captures.push(c) {
+                    self.sender.send(CaptureRejected(c));
+                }
+                else {
+                    self.captures[self.captures.len()-1].alive.store(true, Release);
+                    self.sender.send(CaptureAdded(uu));
+                }
+            },
+            AudioThreadCommand::AddBus(b) => {
+                /* NOTE: This code must mirror the code in lib.rs */
+                if let Some(ix) = self.bus_holes.remove(0) {
+                    self.buses[ix] = Some(b);
+                }
+                else {
+                    self.buses.push(Some(b));
+                }
+            },
+            AudioThreadCommand::RemoveBus(ix) => {
+                /* NOTE: This code must mirror the code in lib.rs */
+                if let Some(b) = self.buses[ix].take() {
+                    self.sender.send(BusRemoved(b));
+                }
+                self.bus_holes.push(ix);
+            },
+            AudioThreadCommand::SetTap(tap) => {
+                if let Some(old) = ::std::mem::replace(&mut self.tap, tap) {
+                    self.sender.send(TapRemoved(old));
+                }
+            },
+            AudioThreadCommand::GroupTransport(id, mut action) => {
+                for player in self.players.iter_mut() {
+                    if player.group.load(Relaxed) != id {
+                        continue;
+                    }
+                    match action {
+                        GroupAction::Start(t) => {
+                            player.start_time.store(t, Relaxed);
+                            player.active.store(true, Relaxed);
+                        },
+                        GroupAction::Pause => player.paused.store(true, Relaxed),
+                        GroupAction::Resume => player.paused.store(false, Relaxed),
+                        GroupAction::Stop { time, kill } => {
+                            player.kill_on_stop.store(kill, Relaxed);
+                            player.stop_position.store(::std::u64::MAX, Relaxed);
+                            player.stop_time.store(time, Relaxed);
+                        },
+                        GroupAction::Seek(ref mut seeks) => {
+                            let seek = seeks.iter_mut()
+                                .find(|&&mut (uu, _)| uu == player.uuid)
+                                .and_then(|&mut (_, ref mut s)| s.take());
+                            if let Some(seek) = seek {
+                                player.apply_seek(seek, time, self.sample_rate, &mut self.sender);
+                            }
+                        }
+                    }
+                }
+                if let GroupAction::Seek(seeks) = action {
+                    self.sender.send(GroupSeeked(id, seeks));
+                }
+            },
+            AudioThreadCommand::Transaction(mut tx) => {
+                if tx.time.map(|t| t <= time).unwrap_or(true) {
+                    self.apply(&mut tx);
+                    self.sender.send(TransactionApplied(tx));
+                }
+                else if let Some(tx) = self.pending.push(tx) {
+                    self.sender.send(TransactionRejected(tx));
+                }
+            }
         }
     }
-}
-impl JackHandler for DeviceContext {
+    /// Apply every change in a transaction, putting whatever they replace back into it.
     #[inline(always)]
-    fn xrun(&mut self) -> JackControl {
-        self.sender.init(0);
-        self.sender.send(Xrun);
-        self.sender.notify();
-        JackControl::Continue
+    fn apply(&mut self, tx: &mut Transaction) {
+        for op in tx.ops.iter_mut() {
+            match *op {
+                Op::SetBool(ref b, val) => b.store(val, Relaxed),
+                Op::SetU64(ref a, val) => a.store(val, Relaxed),
+                // Whatever was in the slot hasn't been picked up, so it's never been used.
+                Op::QueueVolume(ref shared, ref mut val) => *val = val.take().and_then(|v| shared.queue(v)),
+                Op::QueueRouting(ref shared, ref mut val) => *val = val.take().and_then(|v| shared.queue(v)),
+                Op::SetOutputStage(idx, ref mut stage) => {
+                    if let Some(&mut Some(ref mut ch)) = self.chans.get_mut(idx) {
+                        if let Some(new) = stage.take() {
+                            *stage = Some(::std::mem::replace(&mut ch.stage, new));
+                        }
+                    }
+                }
+            }
+        }
     }
+    /// Push this period's audio from the input channels to every active capture.
     #[inline(always)]
-    fn process(&mut self, out: &JackCallbackContext) -> JackControl {
-        let time = time::precise_time_ns();
+    fn capture<C>(&mut self, out: &C, time: u64, nframes: usize) where C: CallbackContext<Port=P> {
+        if self.captures.is_empty() {
+            return;
+        }
+        let mut bufs: ArrayVec<[Option<&[f32]>; MAX_CHANS]> = ArrayVec::new();
+        for port in self.inputs.iter() {
+            let buf = match *port {
+                // Each input channel has its own port, so this is the only borrow of it.
+                Some(ref port) => out.get_port_buffer(port).map(|b| unsafe { &*b }),
+                None => None
+            };
+            bufs.push(buf);
+        }
+        for cap in self.captures.iter_mut() {
+            if !cap.alive.load(Relaxed) {
+                continue;
+            }
+            if !cap.active.load(Relaxed) {
+                continue;
+            }
+            if cap.buf.free_space() < nframes * cap.chans.len() {
+                cap.overflowed.store(true, Relaxed);
+                continue;
+            }
+            if cap.start_time.load(Relaxed) == ::std::u64::MAX {
+                cap.start_time.store(time, Relaxed);
+            }
+            for i in 0..nframes {
+                for &ch in cap.chans.iter() {
+                    let samp = match bufs.get(ch) {
+                        Some(Some(buf)) => buf[i],
+                        _ => 0.0
+                    };
+                    cap.buf.push(samp);
+                }
+            }
+            cap.frames.fetch_add(nframes as u64, Relaxed);
+        }
+        // Remove every dead capture at once, as with players (see the end of `process_at()`).
+        let mut live = 0;
+        for idx in 0..self.captures.len() {
+            if self.captures[idx].alive.load(Relaxed) {
+                if idx != live {
+                    self.captures.swap(live, idx);
+                }
+                live += 1;
+            }
+        }
+        while self.captures.len() > live {
+            if let Some(c) = self.captures.pop() {
+                self.sender.send(CaptureRemoved(c));
+            }
+        }
+    }
+    /// Run one period of audio processing, as if it were happening at `time`.
+    #[inline(always)]
+    pub fn process_at<C>(&mut self, out: &C, time: u64) where C: CallbackContext<Port=P> {
         self.sender.init(time);
-        if let Some(cmd) = self.control.try_pop() {
-            self.handle(cmd);
+        let nframes = out.nframes() as usize;
+        let period_ns = nframes as u64 * ONE_SECOND_IN_NANOSECONDS / self.sample_rate;
+        let end_time = time + period_ns;
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].time.map(|t| t <= time).unwrap_or(true) {
+                if let Some(mut tx) = self.pending.remove(i) {
+                    self.apply(&mut tx);
+                    self.sender.send(TransactionApplied(tx));
+                }
+            }
+            else {
+                i += 1;
+            }
         }
-        let mut to_remove = None;
-        'outer: for (idx, player) in self.players.iter_mut().enumerate() {
-            if !player.alive.load(Relaxed) {
-                if to_remove.is_none() {
-                    to_remove = Some(idx);
+        let budget = ::std::cmp::max(self.command_budget.load(Relaxed), 1);
+        let mut handled = 0;
+        while handled < budget {
+            match self.control.try_pop() {
+                Some(cmd) => self.handle(cmd, time),
+                None => break
+            }
+            handled += 1;
+        }
+        if handled == budget && self.control.size() > 0 {
+            self.sender.send(CommandBudgetExceeded(self.control.size()));
+        }
+        self.capture(out, time, nframes);
+        // Grab (and zero out) every channel's buffer up front, so that players can be mixed
+        // into them frame by frame.
+        let mut bufs: ArrayVec<[Option<&mut [f32]>; MAX_CHANS]> = ArrayVec::new();
+        for ch in self.chans.iter() {
+            let mut buf = match *ch {
+                // Each output channel has its own port, so this is the only borrow of it.
+                Some(ref ch) => out.get_port_buffer(&ch.port).map(|b| unsafe { &mut *b }),
+                None => None
+            };
+            if let Some(ref mut buf) = buf {
+                for x in buf.iter_mut() {
+                    *x = 0.0;
+                }
+            }
+            bufs.push(buf);
+        }
+        let bus_frames = ::std::cmp::min(nframes, MAX_BUFFER_SIZE);
+        for bus in self.buses.iter_mut() {
+            if let Some(ref mut bus) = *bus {
+                for ch in 0..bus.channels {
+                    for x in bus.buf[ch * MAX_BUFFER_SIZE..ch * MAX_BUFFER_SIZE + bus_frames].iter_mut() {
+                        *x = 0.0;
+                    }
                 }
+            }
+        }
+        for player in self.players.iter_mut() {
+            if !player.alive.load(Relaxed) {
                 continue;
             }
+            let seekp = player.seek.swap(::std::ptr::null_mut(), AcqRel);
+            if !seekp.is_null() {
+                let seek = unsafe { Box::from_raw(seekp) };
+                player.apply_seek(seek, time, self.sample_rate, &mut self.sender);
+            }
+            let loopp = player.loop_pending.swap(::std::ptr::null_mut(), AcqRel);
+            if !loopp.is_null() {
+                let mut lp = unsafe { Box::from_raw(loopp) };
+                if let Some(old) = player.lp.take() {
+                    self.sender.send(LoopRemoved(old));
+                }
+                if player.read_pos > lp.start * player.channels as u64 {
+                    // Too late: we've already played past the start of it.
+                    self.sender.send(LoopRemoved(lp));
+                }
+                else {
+                    lp.rewind(player.read_pos, player.channels);
+                    player.lp = Some(lp);
+                }
+            }
+            if let Some(old) = player.routing.pick_up() {
+                self.sender.send(RoutingRemoved(old));
+            }
+            if let Some(old) = player.volume.pick_up() {
+                self.sender.send(VolumeRemoved(old));
+            }
+            // Shared master volumes are picked up by whichever player gets to them first.
+            if let Some(old) = player.master_vol.pick_up() {
+                self.sender.send(VolumeRemoved(old));
+            }
+            // Fades run on the engine's clock whether or not the player is playing, so they're
+            // checked before any of the players that aren't get skipped.
+            let (vol, master_vol) = unsafe { (player.volume.current(), player.master_vol.current()) };
+            if let Some(id) = vol.take_completion(end_time) {
+                self.sender.send(FadeComplete(player.uuid, id.clone()));
+            }
+            if let Some(id) = master_vol.take_completion(end_time) {
+                self.sender.send(MasterFadeComplete(id.clone()));
+            }
             if !player.active.load(Relaxed) {
+                if let Some(old) = player.seek_tail.take() {
+                    self.sender.send(SeekBufferRemoved(old));
+                }
+                continue;
+            }
+            // Stops normally happen partway through a period (see below), but catch any that
+            // were scheduled for a time or position we've already passed.
+            //
+            // These are only loaded once: the main thread can change them at any time, and the
+            // sums below rely on them being after `time` and the player's position.
+            let stop_time = player.stop_time.load(Relaxed);
+            let stop_position = player.stop_position.load(Relaxed);
+            if stop_time <= time || stop_position <= player.position.load(Relaxed) {
+                player.stop(&mut self.sender);
+                continue;
+            }
+            if player.paused.load(Relaxed) {
+                player.was_paused = true;
+                if let Some(old) = player.seek_tail.take() {
+                    self.sender.send(SeekBufferRemoved(old));
+                }
                 continue;
             }
-            let outpatch = player.output_patch.load(Relaxed);
+            if player.was_paused {
+                // We've just been resumed, so pretend we started playing long enough ago to be
+                // where we were paused.
+                player.was_paused = false;
+                if player.start_time.load(Relaxed) <= time {
+                    let pos_ns = player.position.load(Relaxed) * ONE_SECOND_IN_NANOSECONDS / player.sample_rate;
+                    player.start_time.store(time.saturating_sub(pos_ns), Relaxed);
+                }
+            }
             let start_time = player.start_time.load(Relaxed);
+            // The number of frames into this period that the player starts at, if it starts
+            // partway through (i.e. the frame nearest to `start_time`).
+            let mut offset = 0;
             if start_time > time {
                 player.position.store(0, Relaxed);
-                continue;
+                if start_time - time >= period_ns {
+                    continue;
+                }
+                let ns_in = (start_time - time) * self.sample_rate;
+                offset = ((ns_in + ONE_SECOND_IN_NANOSECONDS / 2) / ONE_SECOND_IN_NANOSECONDS) as usize;
+                if offset >= nframes {
+                    continue;
+                }
             }
-            let sample_delta = (time - start_time) * self.sample_rate / ONE_SECOND_IN_NANOSECONDS;
+            // Positions are in frames at the player's sample rate, which may need converting to ours.
+            let chans = player.channels;
+            let resampling = player.sample_rate != self.sample_rate;
+            // Whether the player has started is down to `start_time` alone: a start time less than
+            // half a sample into the period still has an offset of 0.
+            let sample_delta = if start_time > time {
+                0
+            } else {
+                // Times are truncated to the nanosecond, so round to the nearest sample.
+                ((time - start_time) * player.sample_rate + ONE_SECOND_IN_NANOSECONDS / 2) / ONE_SECOND_IN_NANOSECONDS
+            };
             let mut pos = player.position.load(Relaxed);
-            if pos < sample_delta {
-                pos += player.buf.skip_n((sample_delta - pos) as usize) as u64;
+            // When resampling, the position is only accurate to within a sample, so leave some
+            // slack before deciding that we're out of sync.
+            let slack = if resampling { 1 } else { 0 };
+            let mut src = PlayerSource {
+                buf: &player.buf,
+                lp: player.lp.as_mut(),
+                devamp: player.devamp.load(Relaxed),
+                read_pos: &mut player.read_pos,
+                chans
+            };
+            if pos + slack < sample_delta {
+                pos += (src.skip((sample_delta - pos) as usize * chans) / chans) as u64;
+                player.resampler.reset();
             }
-            if pos < sample_delta || player.buf.size() < out.nframes() as usize {
+            let needed = if resampling {
+                player.resampler.source_needed(nframes - offset)
+            } else {
+                nframes - offset
+            };
+            if pos + slack < sample_delta || src.available() < needed * chans {
                 if player.kill_when_empty.load(Relaxed) {
                     player.alive.store(false, Relaxed);
                 }
@@ -143,6 +800,9 @@ impl JackHandler for DeviceContext {
                     self.sender.send(PlayerBufEmpty(player.uuid));
                     player.empty_sent = true;
                 }
+                if let Some(old) = player.seek_tail.take() {
+                    self.sender.send(SeekBufferRemoved(old));
+                }
                 player.position.store(pos, Relaxed);
                 continue;
             }
@@ -153,62 +813,267 @@ impl JackHandler for DeviceContext {
             else if player.buf.size()*2 >= player.buf.capacity() && player.half_sent {
                 player.half_sent = false;
             }
-            if outpatch >= self.chans.len() || self.chans[outpatch].is_none() {
+            let routing = unsafe { player.routing.current() };
+            let buses = &mut self.buses;
+            let valid = routing.points().iter().all(|cp| {
+                cp.source < chans && match cp.dest {
+                    Dest::Channel(c) => c < bufs.len() && bufs[c].is_some(),
+                    Dest::Bus(b, c) => match buses.get(b) {
+                        Some(Some(bus)) => c < bus.channels,
+                        _ => false
+                    }
+                }
+            });
+            if !valid {
                 self.sender.send(PlayerInvalidOutpatch(player.uuid));
                 player.active.store(false, Relaxed);
                 continue;
             }
-            let volp = player.volume.load(Acquire);
-            let master_volp = player.master_vol.load(Acquire);
-            let vol = unsafe {
-                (*volp).get(time)
+            // Evaluate the gain at both ends of the period, and ramp between them, so that fades
+            // don't step at every period boundary.
+            let gain_start = vol.get(time) * master_vol.get(time);
+            let gain_end = vol.get(end_time) * master_vol.get(end_time);
+            let gain_step = (gain_end - gain_start) / nframes as f32;
+            let declick_frames = if player.stop_declick.load(Relaxed) {
+                STOP_DECLICK_TIME * self.sample_rate / ONE_SECOND_IN_NANOSECONDS
+            } else {
+                0
             };
-            let master_vol = unsafe {
-                (*master_volp).get(time)
+            // The number of frames into this period that the player stops at, if it's stopping
+            // (which might well be after the end of this period).
+            //
+            // Stops beyond this period and its declick can't affect it, so they're ignored - which
+            // also keeps the sums below from overflowing for stops far in the future.
+            let horizon = nframes as u64 + declick_frames;
+            let mut stop_in = None;
+            if stop_time != ::std::u64::MAX {
+                let ns = stop_time - time;
+                if ns <= (horizon + 1) * ONE_SECOND_IN_NANOSECONDS / self.sample_rate {
+                    stop_in = Some((ns * self.sample_rate + ONE_SECOND_IN_NANOSECONDS / 2) / ONE_SECOND_IN_NANOSECONDS);
+                }
+            }
+            if stop_position != ::std::u64::MAX {
+                let left = stop_position.saturating_sub(pos);
+                let frames = if !resampling && left <= horizon {
+                    Some(left)
+                } else if resampling && left <= horizon * player.sample_rate / self.sample_rate + 1 {
+                    Some((left * self.sample_rate + player.sample_rate - 1) / player.sample_rate)
+                } else {
+                    None
+                };
+                if let Some(frames) = frames {
+                    let frames = offset as u64 + frames;
+                    stop_in = Some(stop_in.map(|s| ::std::cmp::min(s, frames)).unwrap_or(frames));
+                }
+            }
+            let end = match stop_in {
+                Some(s) if s < nframes as u64 => s as usize,
+                _ => nframes
             };
-            player.volume.store(volp, Release);
-            player.master_vol.store(master_volp, Release);
-            let ch = self.chans[outpatch].as_mut().unwrap();
-            if let Some(buf) = out.get_port_buffer(&ch.port) {
-                let written = time == ch.written_t;
-                if !written {
-                    ch.written_t = time;
+            let metering = player.meters.enabled.load(Relaxed);
+            let mut frame = [0.0; MAX_SENDER_CHANNELS];
+            let mut old_frame = [0.0; MAX_SENDER_CHANNELS];
+            for i in offset..end {
+                if resampling {
+                    pos += player.resampler.next_frame(&mut src, &mut frame[..chans]);
                 }
-                for x in buf.iter_mut() {
-                    if let Some(data) = player.buf.try_pop() {
-                        if written {
-                            *x += data * vol * master_vol;
+                else {
+                    for x in frame[..chans].iter_mut() {
+                        *x = src.next_sample();
+                    }
+                    pos += 1;
+                }
+                if let Some(ref mut tail) = player.seek_tail {
+                    let tail = &mut **tail;
+                    if tail.xfade_left > 0 {
+                        if resampling {
+                            tail.resampler.next_frame(&mut tail.buf, &mut old_frame[..chans]);
                         }
                         else {
-                            *x = data * vol * master_vol;
+                            for x in old_frame[..chans].iter_mut() {
+                                *x = tail.buf.try_pop().unwrap_or(0.0);
+                            }
+                        }
+                        let fade_in = 1.0 - tail.xfade_left as f32 / tail.xfade_len as f32;
+                        for ch in 0..chans {
+                            frame[ch] = frame[ch] * fade_in + old_frame[ch] * (1.0 - fade_in);
+                        }
+                        tail.xfade_left -= 1;
+                    }
+                }
+                let mut gain = gain_start + gain_step * i as f32;
+                if let Some(s) = stop_in {
+                    let left = s - i as u64;
+                    if left < declick_frames {
+                        gain *= left as f32 / declick_frames as f32;
+                    }
+                }
+                if metering {
+                    let meters = frame[..chans].iter()
+                        .zip(player.meter_peak.iter_mut())
+                        .zip(player.meter_sum_squares.iter_mut());
+                    for ((&x, peak), sum_squares) in meters {
+                        let level = x * gain;
+                        *peak = peak.max(level.abs());
+                        *sum_squares += level * level;
+                    }
+                }
+                for cp in routing.points() {
+                    let data = frame[cp.source] * cp.gain * gain;
+                    match cp.dest {
+                        Dest::Channel(c) => {
+                            if let Some(ref mut buf) = bufs[c] {
+                                buf[i] += data;
+                            }
+                        },
+                        Dest::Bus(b, c) => {
+                            if i < bus_frames {
+                                if let Some(ref mut bus) = buses[b] {
+                                    bus.buf[c * MAX_BUFFER_SIZE + i] += data;
+                                }
+                            }
                         }
-                        if *x > 1.0 { *x = 1.0; }
-                        if *x < -1.0 { *x = -1.0; }
-                        pos += 1;
                     }
                 }
             }
             player.position.store(pos, Relaxed);
-        }
-        if let Some(x) = to_remove {
-            if let Some(p) = self.players.swap_remove(x) {
-                self.sender.send(PlayerRemoved(p));
+            if end < nframes || stop_in == Some(nframes as u64) {
+                player.stop(&mut self.sender);
+            }
+            if let Some(ref mut lp) = player.lp {
+                while lp.wraps_sent < lp.wraps {
+                    lp.wraps_sent += 1;
+                    self.sender.send(PlayerLooped(player.uuid, lp.wraps_sent));
+                }
+            }
+            if player.seek_tail.as_ref().map(|t| t.xfade_left == 0).unwrap_or(false) {
+                if let Some(old) = player.seek_tail.take() {
+                    self.sender.send(SeekBufferRemoved(old));
+                }
             }
-            self.length.store(self.length.load(Relaxed) - 1, Relaxed);
         }
-        for ch in self.chans.iter_mut() {
-            if let &mut Some(ref mut ch) = ch {
-                if ch.written_t != time && ch.zeroed_t < ch.written_t {
-                    if let Some(buf) = out.get_port_buffer(&ch.port) {
-                        for x in buf.iter_mut() {
-                            *x = 0.0;
+        // Buses can only be routed to buses with lower numbers, so going backwards means every
+        // bus has been summed into by the time it's processed.
+        for idx in (0..self.buses.len()).rev() {
+            let (lower, rest) = self.buses.split_at_mut(idx);
+            let bus = match rest[0] {
+                Some(ref mut b) => b,
+                None => continue
+            };
+            if let Some(old) = bus.volume.pick_up() {
+                self.sender.send(VolumeRemoved(old));
+            }
+            if let Some(old) = bus.routing.pick_up() {
+                self.sender.send(RoutingRemoved(old));
+            }
+            let (vol, routing) = unsafe { (bus.volume.current(), bus.routing.current()) };
+            if let Some(id) = vol.take_completion(end_time) {
+                self.sender.send(BusFadeComplete(idx, id.clone()));
+            }
+            let (vol_start, vol_end) = (vol.get(time), vol.get(end_time));
+            let vol_step = (vol_end - vol_start) / nframes as f32;
+            for cp in routing.points() {
+                if cp.source >= bus.channels {
+                    continue;
+                }
+                let src = &bus.buf[cp.source * MAX_BUFFER_SIZE..cp.source * MAX_BUFFER_SIZE + bus_frames];
+                let gain_start = cp.gain * vol_start;
+                let gain_step = cp.gain * vol_step;
+                match cp.dest {
+                    Dest::Channel(c) => {
+                        if let Some(&mut Some(ref mut buf)) = bufs.get_mut(c) {
+                            for (i, (x, &s)) in buf.iter_mut().zip(src.iter()).enumerate() {
+                                *x += s * (gain_start + gain_step * i as f32);
+                            }
+                        }
+                    },
+                    Dest::Bus(b, c) => {
+                        if let Some(&mut Some(ref mut dest)) = lower.get_mut(b) {
+                            if c < dest.channels {
+                                let dest = &mut dest.buf[c * MAX_BUFFER_SIZE..c * MAX_BUFFER_SIZE + bus_frames];
+                                for (i, (x, &s)) in dest.iter_mut().zip(src.iter()).enumerate() {
+                                    *x += s * (gain_start + gain_step * i as f32);
+                                }
+                            }
                         }
                     }
-                    ch.zeroed_t = time;
                 }
             }
         }
+        for player in self.players.iter_mut() {
+            if player.meters.enabled.load(Relaxed) {
+                for (ch, meter) in player.meters.meters.iter().enumerate() {
+                    meter.update(player.meter_peak[ch], player.meter_sum_squares[ch], nframes, time, period_ns, &self.meters.ballistics);
+                    player.meter_peak[ch] = 0.0;
+                    player.meter_sum_squares[ch] = 0.0;
+                }
+            }
+        }
+        // Now that every channel has been completely mixed, run it through its output stage,
+        // and meter the result.
+        for (idx, (ch, buf)) in self.chans.iter_mut().zip(bufs.iter_mut()).enumerate() {
+            if let (&mut Some(ref mut ch), &mut Some(ref mut buf)) = (ch, buf) {
+                ch.stage.process(buf);
+                let mut peak = 0.0f32;
+                let mut sum_squares = 0.0;
+                for &x in buf.iter() {
+                    peak = peak.max(x.abs());
+                    sum_squares += x * x;
+                }
+                self.meters.chans[idx].update(peak, sum_squares, nframes, time, period_ns, &self.meters.ballistics);
+            }
+        }
+        // Move every live player to the front (keeping them in order), and then remove all the
+        // dead ones from the end.
+        let mut live = 0;
+        for idx in 0..self.players.len() {
+            if self.players[idx].alive.load(Relaxed) {
+                if idx != live {
+                    self.players.swap(live, idx);
+                }
+                live += 1;
+            }
+        }
+        if live < self.players.len() {
+            let removed = self.players.len() - live;
+            while self.players.len() > live {
+                if let Some(p) = self.players.pop() {
+                    self.sender.send(PlayerRemoved(p));
+                }
+            }
+            self.length.store(self.length.load(Relaxed) - removed, Relaxed);
+        }
+        if let Some(ref tap) = self.tap {
+            if tap.buf.free_space() < nframes * tap.chans.len() {
+                tap.overflowed.store(true, Relaxed);
+            }
+            else {
+                for i in 0..nframes {
+                    for &ch in tap.chans.iter() {
+                        let samp = match bufs.get(ch) {
+                            Some(Some(buf)) => buf[i],
+                            _ => 0.0
+                        };
+                        tap.buf.push(samp);
+                    }
+                }
+            }
+        }
+        self.sender.notify();
+    }
+}
+impl JackHandler for DeviceContext<JackPort> {
+    #[inline(always)]
+    fn xrun(&mut self) -> JackControl {
+        self.sender.init(0);
+        self.sender.send(Xrun);
         self.sender.notify();
         JackControl::Continue
     }
+    #[inline(always)]
+    fn process(&mut self, out: &JackCallbackContext) -> JackControl {
+        let time = self.clock.period_time();
+        self.process_at(out, time);
+        JackControl::Continue
+    }
 }
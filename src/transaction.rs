@@ -0,0 +1,140 @@
+//! Transactions, for changing several things on the audio thread at once.
+//!
+//! Changes made through a `Sender` (or a `Bus`) are each picked up by the audio thread as soon as
+//! they're made, so a patch change, a volume change and an activation made one after another can
+//! land in different periods. Changes batched up in a `Transaction` (obtained from
+//! `EngineContext::new_transaction()`) and submitted with `EngineContext::submit()` are instead
+//! applied together, at the start of one period - either straight away, or at a given time.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use errors::EngineResult;
+use param::Parameter;
+use routing::Routing;
+use stage::{ChannelStage, OutputStage};
+use bus::Bus;
+use sync::SharedValue;
+use super::Sender;
+
+/// One change in a transaction.
+///
+/// Anything replaced by the audio thread ends up back in here, to be deallocated on the main thread.
+pub(crate) enum Op {
+    SetBool(Arc<AtomicBool>, bool),
+    SetU64(Arc<AtomicU64>, u64),
+    /// Leaves a volume for the audio thread to pick up, like `Sender::set_volume()` does; the
+    /// main thread's copy of the volume is updated when the transaction is submitted.
+    QueueVolume(Arc<SharedValue<Parameter<f32>>>, Option<Box<Parameter<f32>>>),
+    /// Leaves a routing matrix for the audio thread to pick up, like `Sender::set_routing()`
+    /// does; the main thread's copy of the matrix is updated when the transaction is submitted.
+    QueueRouting(Arc<SharedValue<Routing>>, Option<Box<Routing>>),
+    SetOutputStage(usize, Option<ChannelStage>)
+}
+
+/// A batch of changes to streams, buses and channels, applied by the audio thread all at once.
+///
+/// The changes are applied in the order they were added, at the start of the first period that
+/// begins at or after the transaction's time (or the next period, if it doesn't have one). The
+/// audio thread sends the transaction back with a `TransactionApplied` message once it's done.
+pub struct Transaction {
+    pub(crate) time: Option<u64>,
+    pub(crate) ops: Vec<Op>,
+    sample_rate: u64
+}
+impl Transaction {
+    pub(crate) fn new(sample_rate: u64) -> Self {
+        Transaction {
+            time: None,
+            ops: vec![],
+            sample_rate
+        }
+    }
+    /// Set the time (from the engine's clock) to apply this transaction at, or `None` to apply
+    /// it as soon as possible.
+    pub fn set_time(&mut self, time: Option<u64>) {
+        self.time = time;
+    }
+    /// Get the time this transaction will be applied at, if it has one.
+    pub fn time(&self) -> Option<u64> {
+        self.time
+    }
+    /// Update the main thread's copies of everything this transaction changes, so that getters
+    /// like `Sender::routing()` reflect it from when it's submitted.
+    pub(crate) fn update_copies(&self) {
+        for op in self.ops.iter() {
+            match *op {
+                Op::QueueVolume(ref shared, Some(ref new)) => shared.set_copy(new),
+                Op::QueueRouting(ref shared, Some(ref new)) => shared.set_copy(new),
+                _ => {}
+            }
+        }
+    }
+    /// Get the number of changes in this transaction.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    /// Get whether this transaction has no changes in it.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    /// Set whether a stream will play samples or not, as with `Sender::set_active()`.
+    pub fn set_active<T>(&mut self, s: &Sender<T>, active: bool) {
+        self.ops.push(Op::SetBool(s.active.clone(), active));
+    }
+    /// Set a stream's start time, as with `Sender::set_start_time()`.
+    pub fn set_start_time<T>(&mut self, s: &Sender<T>, time: u64) {
+        self.ops.push(Op::SetU64(s.start_time.clone(), time));
+    }
+    /// Start a stream playing, as if it was supposed to start at a given time, as with
+    /// `Sender::play_from_time()`.
+    pub fn play_from_time<T>(&mut self, s: &Sender<T>, time: u64) {
+        self.set_start_time(s, time);
+        self.set_active(s, true);
+    }
+    /// Pause or resume a stream, as with `Sender::pause()` and `Sender::resume()`.
+    pub fn set_paused<T>(&mut self, s: &Sender<T>, paused: bool) {
+        self.ops.push(Op::SetBool(s.paused.clone(), paused));
+    }
+    /// Set a stream's volume, as with `Sender::set_volume()`.
+    ///
+    /// The stream's `volume()` returns the new volume as soon as the transaction is submitted.
+    pub fn set_volume<T>(&mut self, s: &Sender<T>, vol: Box<Parameter<f32>>) {
+        self.ops.push(Op::QueueVolume(s.volume.clone(), Some(vol)));
+    }
+    /// Set a stream's master volume, as with `Sender::set_master_volume()`.
+    pub fn set_master_volume<T>(&mut self, s: &Sender<T>, vol: Box<Parameter<f32>>) {
+        self.ops.push(Op::QueueVolume(s.master_vol.clone(), Some(vol)));
+    }
+    /// Set a stream's routing matrix, as with `Sender::set_routing()`.
+    ///
+    /// The stream's `routing()` returns the new matrix as soon as the transaction is submitted.
+    pub fn set_routing<T>(&mut self, s: &Sender<T>, routing: Routing) {
+        self.ops.push(Op::QueueRouting(s.routing.clone(), Some(Box::new(routing))));
+    }
+    /// Set a stream's output patch, as with `Sender::set_output_patch()`.
+    ///
+    /// This is worked out from the stream's routing matrix as it is now, so it'll undo any
+    /// other routing changes made between now and when the transaction is applied.
+    pub fn set_output_patch<T>(&mut self, s: &Sender<T>, patch: usize) {
+        let mut routing = s.routing();
+        routing.clear_source(0);
+        routing.set(0, patch, 1.0);
+        self.set_routing(s, routing);
+    }
+    /// Set a bus's volume, as with `Bus::set_volume()`.
+    pub fn set_bus_volume(&mut self, bus: &Bus, vol: Box<Parameter<f32>>) {
+        self.ops.push(Op::QueueVolume(bus.volume.clone(), Some(vol)));
+    }
+    /// Set a bus's routing matrix, as with `Bus::set_routing()` (and with the same checks).
+    pub fn set_bus_routing(&mut self, bus: &Bus, routing: Routing) -> EngineResult<()> {
+        bus.check_routing(&routing)?;
+        self.ops.push(Op::QueueRouting(bus.routing.clone(), Some(Box::new(routing))));
+        Ok(())
+    }
+    /// Set a channel's output stage, as with `EngineContext::set_output_stage()`.
+    ///
+    /// If the channel doesn't exist when the transaction is applied, this is ignored.
+    pub fn set_output_stage(&mut self, idx: usize, stage: OutputStage) {
+        self.ops.push(Op::SetOutputStage(idx, Some(ChannelStage::new(stage, self.sample_rate))));
+    }
+}
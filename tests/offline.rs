@@ -0,0 +1,373 @@
+//! Sample-accurate behaviour of streams, checked by rendering with the offline backend.
+// As in the crate itself, `u64::MAX` and tool lints are too new for this crate's toolchain.
+#![cfg_attr(clippy, allow(clippy::unnecessary_clippy_cfg))]
+#![cfg_attr(clippy, allow(clippy::legacy_numeric_constants))]
+extern crate sqa_engine;
+
+use sqa_engine::{EngineContext, BufferSender, ONE_SECOND_IN_NANOSECONDS};
+use sqa_engine::offline::OfflineBackend;
+use sqa_engine::clock::{Clock, MonotonicClock, VirtualClock};
+use sqa_engine::param::{Parameter, FadeDetails};
+use sqa_engine::resample::ResampleQuality;
+use sqa_engine::routing::{Dest, Routing};
+use sqa_engine::stage::OutputStage;
+use sqa_engine::sync::AudioThreadMessage;
+use std::sync::Arc;
+
+const SAMPLE_RATE: u64 = 48000;
+const BUFFER_SIZE: u32 = 64;
+
+/// The time `frames` frames after `time`.
+fn frames_after(time: u64, frames: u64) -> u64 {
+    time + frames * ONE_SECOND_IN_NANOSECONDS / SAMPLE_RATE
+}
+/// A distinct sample value for `i`, small enough to get through the output clamp untouched.
+fn sample(i: u32) -> f32 {
+    i as f32 / 256.0
+}
+/// Makes an engine with `chans` output channels.
+fn engine(chans: usize) -> EngineContext<OfflineBackend> {
+    let mut ec = EngineContext::offline(SAMPLE_RATE, BUFFER_SIZE);
+    for i in 0..chans {
+        ec.new_channel(&format!("out {}", i)).unwrap();
+    }
+    ec
+}
+/// Makes a stream patched to channel 0, and fills its buffer with `val`.
+fn sender(ec: &mut EngineContext<OfflineBackend>, val: f32) -> BufferSender {
+    let mut s = ec.new_sender(SAMPLE_RATE);
+    s.set_output_patch(0);
+    for _ in 0..(BUFFER_SIZE * 4) {
+        s.buf.push(val);
+    }
+    s
+}
+/// Renders `periods` periods, returning what was rendered to channel `ch`.
+fn render(ec: &mut EngineContext<OfflineBackend>, periods: usize, ch: usize) -> Vec<f32> {
+    let mut ret = vec![];
+    for _ in 0..periods {
+        ec.render();
+        ret.extend_from_slice(ec.buffer(ch).unwrap());
+    }
+    ret
+}
+
+#[test]
+fn starts_on_the_right_frame() {
+    let mut ec = engine(1);
+    let mut s = sender(&mut ec, 0.5);
+    let now = ec.now();
+    s.play_from_time(frames_after(now, 100));
+    let out = render(&mut ec, 2, 0);
+    assert!(out[..100].iter().all(|&x| x == 0.0));
+    assert!(out[100..].iter().all(|&x| x == 0.5));
+}
+#[test]
+fn starts_less_than_half_a_sample_in() {
+    let mut ec = engine(1);
+    let mut s = sender(&mut ec, 0.5);
+    let now = ec.now();
+    s.play_from_time(now + 5);
+    let out = render(&mut ec, 2, 0);
+    assert!(out.iter().all(|&x| x == 0.5));
+}
+#[test]
+fn stops_on_the_right_frame() {
+    let mut ec = engine(1);
+    let mut hdl = ec.get_handle().unwrap();
+    let mut s = sender(&mut ec, 0.5);
+    let now = ec.now();
+    s.play_from_time(now);
+    s.stop_at(frames_after(now, 100), false);
+    let out = render(&mut ec, 2, 0);
+    assert!(out[..100].iter().all(|&x| x == 0.5));
+    assert!(out[100..].iter().all(|&x| x == 0.0));
+    assert!(!s.active());
+    let mut stopped = false;
+    while let Some(msg) = hdl.try_recv() {
+        if let AudioThreadMessage::PlayerStopped(uu) = msg {
+            stopped = uu == s.uuid();
+        }
+    }
+    assert!(stopped);
+}
+#[test]
+fn routes_to_the_right_channel() {
+    let mut ec = engine(2);
+    let mut s = sender(&mut ec, 0.5);
+    s.remove_crosspoint(0, 0);
+    s.set_crosspoint(0, 1, 0.5);
+    let now = ec.now();
+    s.play_from_time(now);
+    ec.render();
+    assert!(ec.buffer(0).unwrap().iter().all(|&x| x == 0.0));
+    assert!(ec.buffer(1).unwrap().iter().all(|&x| x == 0.25));
+}
+#[test]
+fn routes_through_a_bus() {
+    let mut ec = engine(2);
+    let bus = ec.new_bus(1).unwrap();
+    {
+        let bus = ec.bus(bus).unwrap();
+        bus.set_volume(Box::new(Parameter::Raw(0.5)));
+        bus.set_routing(Routing::patch(0, 1)).unwrap();
+    }
+    let mut s = sender(&mut ec, 0.5);
+    s.set_routing(Routing::patch(0, Dest::Bus(bus, 0)));
+    let now = ec.now();
+    s.play_from_time(now);
+    ec.render();
+    assert!(ec.buffer(0).unwrap().iter().all(|&x| x == 0.0));
+    assert!(ec.buffer(1).unwrap().iter().all(|&x| x == 0.25));
+}
+#[test]
+fn only_runs_on_its_own_clock() {
+    let backend = OfflineBackend::new(SAMPLE_RATE, BUFFER_SIZE);
+    let clock: Arc<dyn Clock> = backend.clock().clone();
+    assert!(EngineContext::with_backend_and_clock(backend, clock).is_ok());
+    let backend = OfflineBackend::new(SAMPLE_RATE, BUFFER_SIZE);
+    assert!(EngineContext::with_backend_and_clock(backend, Arc::new(MonotonicClock)).is_err());
+    let backend = OfflineBackend::new(SAMPLE_RATE, BUFFER_SIZE);
+    assert!(EngineContext::with_backend_and_clock(backend, Arc::new(VirtualClock::new(0))).is_err());
+}
+#[test]
+fn removes_every_dead_capture_at_once() {
+    let mut ec = engine(0);
+    let mut hdl = ec.get_handle().unwrap();
+    ec.new_input_channel("in").unwrap();
+    let mut recvs: Vec<_> = (0..3).map(|_| ec.new_receiver(&[0]).unwrap()).collect();
+    ec.render();
+    let keep = recvs.pop().unwrap();
+    recvs.clear();
+    ec.render();
+    let mut removed = 0;
+    while let Some(msg) = hdl.try_recv() {
+        if let AudioThreadMessage::CaptureRemoved(_) = msg {
+            removed += 1;
+        }
+    }
+    assert_eq!(removed, 2);
+    assert!(keep.alive());
+}
+#[test]
+fn ignores_stops_far_in_the_future() {
+    let mut ec = engine(1);
+    let mut s = sender(&mut ec, 0.5);
+    let now = ec.now();
+    s.play_from_time(now);
+    s.stop_at(now + ::std::u64::MAX / SAMPLE_RATE + 1, false);
+    let out = render(&mut ec, 2, 0);
+    assert!(out.iter().all(|&x| x == 0.5));
+    s.stop_at_position(::std::u64::MAX - 1, false);
+    let out = render(&mut ec, 1, 0);
+    assert!(out.iter().all(|&x| x == 0.5));
+    assert!(s.active());
+}
+#[test]
+fn loops_the_region_and_carries_on() {
+    let mut ec = engine(1);
+    let mut hdl = ec.get_handle().unwrap();
+    let mut s = ec.new_sender(SAMPLE_RATE);
+    s.set_output_patch(0);
+    // Frames 10, 11 and 12 are the loop region, so they aren't in the buffer.
+    for i in (0..10).chain(13..100) {
+        s.buf.push(sample(i));
+    }
+    s.set_loop(10, vec![sample(100), sample(101), sample(102)], Some(1)).unwrap();
+    let now = ec.now();
+    s.play_from_time(now);
+    let out = render(&mut ec, 1, 0);
+    let expected: Vec<f32> = (0..10).chain(vec![100, 101, 102, 100, 101, 102]).chain(13..61)
+        .map(sample)
+        .collect();
+    assert_eq!(out, expected);
+    let mut looped = vec![];
+    while let Some(msg) = hdl.try_recv() {
+        if let AudioThreadMessage::PlayerLooped(uu, n) = msg {
+            assert_eq!(uu, s.uuid());
+            looped.push(n);
+        }
+    }
+    assert_eq!(looped, vec![1]);
+}
+#[test]
+fn leaves_the_region_once_devamped() {
+    let mut ec = engine(1);
+    let mut s = ec.new_sender(SAMPLE_RATE);
+    s.set_output_patch(0);
+    for i in 4..200 {
+        s.buf.push(sample(i));
+    }
+    let region: Vec<f32> = (100..104).map(sample).collect();
+    s.set_loop(0, region.clone(), None).unwrap();
+    let now = ec.now();
+    s.play_from_time(now);
+    let out = render(&mut ec, 2, 0);
+    assert!(out.chunks(4).all(|f| f == &region[..]));
+    s.devamp();
+    let out = render(&mut ec, 1, 0);
+    // The last period ended on the end of the region, which had already jumped back to the
+    // start by then, so it's played through once more.
+    let expected: Vec<f32> = (100..104).chain(4..64).map(sample).collect();
+    assert_eq!(out, expected);
+}
+#[test]
+fn resumes_from_where_it_was_paused() {
+    let mut ec = engine(1);
+    let mut s = ec.new_sender(SAMPLE_RATE);
+    s.set_output_patch(0);
+    for i in 0..200 {
+        s.buf.push(sample(i));
+    }
+    let now = ec.now();
+    s.play_from_time(now);
+    let out = render(&mut ec, 1, 0);
+    assert_eq!(out, (0..64).map(sample).collect::<Vec<_>>());
+    s.pause();
+    let out = render(&mut ec, 2, 0);
+    assert!(out.iter().all(|&x| x == 0.0));
+    assert_eq!(s.position_samples(), 64);
+    s.resume();
+    let out = render(&mut ec, 1, 0);
+    assert_eq!(out, (64..128).map(sample).collect::<Vec<_>>());
+    assert_eq!(s.position_samples(), 128);
+    assert!(s.active());
+}
+#[test]
+fn plays_at_its_own_sample_rate() {
+    let mut ec = engine(1);
+    let mut s = ec.new_multichannel_sender(SAMPLE_RATE / 2, 1, ResampleQuality::Linear).unwrap();
+    s.set_output_patch(0);
+    for i in 0..200 {
+        s.buf.push(sample(i));
+    }
+    let now = ec.now();
+    s.play_from_time(now);
+    let out = render(&mut ec, 2, 0);
+    // Each source frame lasts for two output frames, and is linearly interpolated across.
+    assert_eq!(out, (0..128).map(|i| i as f32 / 512.0).collect::<Vec<_>>());
+    assert_eq!(s.position_samples(), 64);
+}
+#[test]
+fn limits_to_the_ceiling() {
+    let mut ec = engine(1);
+    ec.set_output_stage(0, OutputStage::limiter(0.5)).unwrap();
+    let mut s = ec.new_sender(SAMPLE_RATE);
+    s.set_output_patch(0);
+    for i in 0..(BUFFER_SIZE * 8) {
+        // A square wave, well over the ceiling.
+        s.buf.push(if i % 32 < 16 { 0.9 } else { -0.9 });
+    }
+    let now = ec.now();
+    s.play_from_time(now);
+    let out = render(&mut ec, 8, 0);
+    assert!(out.iter().all(|&x| x.abs() <= 0.5));
+    // Once the look-ahead delay's passed, it comes up to the ceiling.
+    assert!(out.iter().any(|&x| x.abs() > 0.45));
+}
+#[test]
+fn applies_transactions_at_their_time() {
+    let mut ec = engine(2);
+    let mut hdl = ec.get_handle().unwrap();
+    let mut a = sender(&mut ec, 0.5);
+    let b = sender(&mut ec, 0.25);
+    let now = ec.now();
+    a.play_from_time(now);
+    let at = frames_after(now, 2 * BUFFER_SIZE as u64);
+    let mut tx = ec.new_transaction();
+    tx.set_time(Some(at));
+    tx.set_volume(&a, Box::new(Parameter::Raw(0.5)));
+    tx.set_output_patch(&b, 1);
+    tx.play_from_time(&b, at);
+    ec.submit(tx);
+    assert_eq!(a.volume().get(now), 0.5);
+    let out = render(&mut ec, 2, 0);
+    assert!(out.iter().all(|&x| x == 0.5));
+    assert!(!b.active());
+    ec.render();
+    assert!(ec.buffer(0).unwrap().iter().all(|&x| x == 0.25));
+    assert!(ec.buffer(1).unwrap().iter().all(|&x| x == 0.25));
+    let mut applied = false;
+    while let Some(msg) = hdl.try_recv() {
+        if let AudioThreadMessage::TransactionApplied(_) = msg {
+            applied = true;
+        }
+    }
+    assert!(applied);
+}
+#[test]
+fn reports_going_over_the_command_budget() {
+    let mut ec = engine(1);
+    let mut hdl = ec.get_handle().unwrap();
+    ec.render();
+    while hdl.try_recv().is_some() {}
+    ec.set_command_budget(2);
+    let _senders: Vec<_> = (0..5).map(|_| ec.new_sender(SAMPLE_RATE)).collect();
+    for &(added, left) in &[(2, Some(3)), (2, Some(1)), (1, None)] {
+        ec.render();
+        let mut n_added = 0;
+        let mut exceeded = None;
+        while let Some(msg) = hdl.try_recv() {
+            match msg {
+                AudioThreadMessage::PlayerAdded(_) => n_added += 1,
+                AudioThreadMessage::CommandBudgetExceeded(n) => exceeded = Some(n),
+                _ => {}
+            }
+        }
+        assert_eq!(n_added, added);
+        assert_eq!(exceeded, left);
+    }
+}
+#[test]
+fn removes_every_dead_player_at_once() {
+    let mut ec = engine(1);
+    let mut hdl = ec.get_handle().unwrap();
+    let mut senders: Vec<_> = (0..4).map(|_| sender(&mut ec, 0.25)).collect();
+    ec.render();
+    let mut keep = senders.remove(2);
+    senders.clear();
+    ec.render();
+    let mut removed = 0;
+    while let Some(msg) = hdl.try_recv() {
+        if let AudioThreadMessage::PlayerRemoved(p) = msg {
+            assert!(p.uuid != keep.uuid());
+            removed += 1;
+        }
+    }
+    assert_eq!(removed, 3);
+    assert!(keep.alive());
+    let now = ec.now();
+    keep.play_from_time(now);
+    let out = render(&mut ec, 1, 0);
+    assert!(out.iter().all(|&x| x == 0.25));
+}
+#[test]
+fn reports_master_fades_once_even_when_paused() {
+    let mut ec = engine(1);
+    let mut hdl = ec.get_handle().unwrap();
+    let mut a = sender(&mut ec, 0.25);
+    let mut b = ec.new_sender_with_master(&a);
+    let now = ec.now();
+    a.play_from_time(now);
+    b.play_from_time(now);
+    a.pause();
+    b.pause();
+    let mut fd = FadeDetails::new(1.0, 0.0);
+    fd.set_duration_nanos(frames_after(0, BUFFER_SIZE as u64 / 2));
+    fd.start_from_time(now);
+    let id = fd.id_ptr().clone();
+    a.set_master_volume(Box::new(Parameter::Fade(fd)));
+    render(&mut ec, 2, 0);
+    let mut reported = 0;
+    while let Some(msg) = hdl.try_recv() {
+        match msg {
+            AudioThreadMessage::MasterFadeComplete(i) => {
+                assert!(Arc::ptr_eq(&i, &id));
+                reported += 1;
+            },
+            AudioThreadMessage::FadeComplete(..) => panic!("master fade reported as a stream's"),
+            _ => {}
+        }
+    }
+    assert_eq!(reported, 1);
+}